@@ -1,9 +1,13 @@
 use chrono::prelude::*;
+use chrono::Duration;
 use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Cell {
     Empty,
     Wall,
@@ -11,67 +15,502 @@ pub enum Cell {
     OpenGate,
     HiddenGate,
     Ghost,
+    // A brick with a power-up under it, and the power-up once the brick burnt.
+    HiddenPowerUp(PowerUp),
+    PowerUp(PowerUp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PowerUp {
+    ExtraBomb,
+    Flame,
+    Speed,
 }
 
 type Coord = (usize, usize);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Eq, PartialEq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    fn offset(self) -> (i8, i8) {
+        match self {
+            Direction::Up => (-1, 0),
+            Direction::Down => (1, 0),
+            Direction::Left => (0, -1),
+            Direction::Right => (0, 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rejection {
+    Throttled,
+    Blocked,
+    BlockedByBomb,
+    GameOver,
+    NoBombsLeft,
+    BombInPlace,
+}
+
+// Player input (Joined, Turn, Moved, MoveRejected, BombPlanted) is replayed as
+// is; GhostsMoved and Blast mark the instants the lazy simulation advanced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    Created {
+        template: Vec<String>,
+        settings: Settings,
+    },
+    Joined {
+        player: usize,
+        coords: Coord,
+    },
+    Turn {
+        turn: u64,
+    },
+    Moved {
+        player: usize,
+        direction: Direction,
+        to: Coord,
+    },
+    MoveRejected {
+        player: usize,
+        direction: Direction,
+        reason: Rejection,
+    },
+    BombPlanted {
+        player: usize,
+        coords: Coord,
+    },
+    GhostsMoved {
+        ghosts: Vec<Coord>,
+    },
+    Blast {
+        coords: Coord,
+        destroyed: Vec<Coord>,
+        // Every cell the flames reached.
+        #[serde(default)]
+        area: Vec<Coord>,
+        // Bombs this blast set off in a chain reaction.
+        #[serde(default)]
+        chained: Vec<Coord>,
+    },
+    Finished {
+        player: usize,
+        outcome: Outcome,
+    },
+    PowerUpCollected {
+        player: usize,
+        power_up: PowerUp,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(tag = "name")]
+pub enum Commands {
+    MoveBomberman { direction: Direction },
+    FetchSurrounding,
+    FetchBombSurrounding,
+    PlantBomb,
+    FetchBombStatus,
+    FetchGameStatus,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Command {
+    pub command: Commands,
+    pub additional: Option<Box<Command>>,
+}
+
+pub fn flatten(command: &Command) -> Vec<&Commands> {
+    let mut result = vec![];
+    let mut current: &Command = command;
+    loop {
+        result.push(&current.command);
+        match &current.additional {
+            None => {
+                break;
+            }
+            Some(v) => {
+                current = v;
+                continue;
+            }
+        };
+    }
+    result
+}
+
+// The commands a protocol version acts on. Anything else in a chain is
+// answered with Reply::Skipped.
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+    pub bombs: bool,
+    pub bomb_surrounding: bool,
+    pub game_status: bool,
+}
+
+impl Capabilities {
+    pub const ALL: Capabilities = Capabilities {
+        bombs: true,
+        bomb_surrounding: true,
+        game_status: true,
+    };
+
+    fn allow(&self, command: &Commands) -> bool {
+        match command {
+            Commands::MoveBomberman { .. } | Commands::FetchSurrounding => true,
+            Commands::PlantBomb | Commands::FetchBombStatus => self.bombs,
+            Commands::FetchBombSurrounding => self.bomb_surrounding,
+            Commands::FetchGameStatus => self.game_status,
+        }
+    }
+}
+
+// What one command of a chain produced.
+pub enum Reply {
+    Done(Result<(), Rejection>),
+    Skipped,
+    Surrounding(Surroundings),
+    BombStatus(Vec<BombStatus>),
+    BombSurrounding(Option<Surroundings>),
+    GameStatus(GameStatus),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Game {
     pub width: usize,
     pub height: usize,
+    #[serde(with = "landscape")]
     landscape: HashMap<Coord, Cell>,
     pub started: DateTime<Utc>,
     pub updated: DateTime<Utc>,
-    bomberman: (usize, usize),
+    bombermen: Vec<Bomberman>,
+    spawns: Vec<Coord>,
     active: bool,
     surrounding_size: usize,
-    bomb: Option<(DateTime<Utc>, (usize, usize))>,
+    bombs: Vec<Bomb>,
+    blast_radius: usize,
+    #[serde(default = "default_bomb_capacity")]
+    bomb_capacity: usize,
+    ghosts: Vec<Ghost>,
+    ghost_step: i64,
+    ghosts_moved: DateTime<Utc>,
+    rng: ChaCha8Rng,
+    clock: Clock,
+    #[serde(default)]
+    mode: Mode,
+    #[serde(default)]
+    turn: u64,
+    // Kept out of snapshots: storage appends it to a file of its own.
+    #[serde(skip)]
+    log: Vec<LogEntry>,
+    // Left out of the game listing unless an admin asks.
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+// In turn-based games every command chain is one turn: the manual clock moves
+// on by TURN_MILLIS, so ghosts step once and fuses burn down one turn per
+// chain, and a second move within the same chain is throttled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Mode {
+    #[default]
+    #[serde(rename = "realtime")]
+    RealTime,
+    #[serde(rename = "turns")]
+    Turns,
+}
+
+const TURN_MILLIS: i64 = 1000;
+
+fn default_bomb_capacity() -> usize {
+    1
 }
 
+// Where a game takes its time from. Manual clocks only move when advanced,
+// which makes throttling, fuses and ghost steps replayable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Clock {
+    #[default]
+    Wall,
+    Manual(DateTime<Utc>),
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime<Utc> {
+        match self {
+            Clock::Wall => Utc::now(),
+            Clock::Manual(now) => *now,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Bomberman {
+    coords: Coord,
+    token: Uuid,
+    moved: DateTime<Utc>,
+    outcome: Option<Outcome>,
+    finished: Option<DateTime<Utc>>,
+    #[serde(default)]
+    extra_bombs: usize,
+    #[serde(default)]
+    extra_flame: usize,
+    #[serde(default)]
+    speed: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Bomb {
+    owner: usize,
+    planted: DateTime<Utc>,
+    coords: Coord,
+    radius: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    ReachedGate,
+    KilledByGhost,
+    KilledByBomb,
+    RivalReachedGate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Running,
+    Won,
+    Lost,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GhostBehaviour {
+    Wander,
+    Corridor,
+    Chase,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Ghost {
+    coords: Coord,
+    behaviour: GhostBehaviour,
+    heading: (i8, i8),
+}
+
+// JSON objects only have string keys, so the landscape is stored as a list of
+// coordinate and cell pairs.
+mod landscape {
+    use super::{Cell, Coord};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S: Serializer>(m: &HashMap<Coord, Cell>, s: S) -> Result<S::Ok, S::Error> {
+        let mut entries: Vec<(&Coord, &Cell)> = m.iter().collect();
+        entries.sort_by_key(|e| *e.0);
+        s.collect_seq(entries)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<HashMap<Coord, Cell>, D::Error> {
+        let entries: Vec<(Coord, Cell)> = Vec::deserialize(d)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+const DIRECTIONS: [(i8, i8); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+const MAX_GHOST_STEPS: i64 = 64;
+const MAX_PLAYERS: usize = 4;
+const THROTTLE_MILLIS: i64 = 200;
+const SPEED_STEP_MILLIS: i64 = 50;
+const MIN_THROTTLE_MILLIS: i64 = 50;
+const FUSE_MILLIS: i64 = 4000;
+
 pub struct Surroundings {
     pub bricks: Vec<(usize, usize)>,
     pub wall: Vec<(usize, usize)>,
     pub bombermans: Vec<(usize, usize)>,
     pub ghosts: Vec<(usize, usize)>,
     pub gates: Vec<(usize, usize)>,
+    pub power_ups: Vec<((usize, usize), PowerUp)>,
+    pub bombs: Vec<(usize, usize)>,
 }
 
 pub struct BombStatus {
     pub coords: (usize, usize),
+    // Milliseconds since the game started, like `GameStatus::elapsed`.
+    pub planted: i64,
+    // Milliseconds left until the blast.
+    pub fuse: i64,
+    // Turns left until the blast, in turn-based games.
+    pub turns: Option<u64>,
+    // Cells the blast will reach if nothing changes before it goes off.
+    pub area: Vec<(usize, usize)>,
+}
+
+pub struct GameStatus {
+    pub status: Status,
+    pub outcome: Option<Outcome>,
+    pub elapsed: i64,
+    pub turn: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinRejection {
+    NoSpawnPoints,
+    Full,
+    GameOver,
+}
+
+pub struct Player {
+    pub index: usize,
+    pub token: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub blast_radius: usize,
+    // How many bombs each bomberman may have ticking at once.
+    pub bombs: usize,
+    pub seed: Option<u64>,
+    pub mode: Mode,
+    pub hidden: bool,
+    #[serde(skip)]
+    pub clock: Clock,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            blast_radius: 1,
+            bombs: 1,
+            seed: None,
+            mode: Mode::default(),
+            hidden: false,
+            clock: Clock::default(),
+        }
+    }
 }
 
 impl Game {
-    pub fn bomb_status(&mut self) -> Option<BombStatus> {
-        self.blast();
-        self.bomb.map(|b| BombStatus { coords: b.1 })
+    pub fn join(&mut self) -> Result<Player, JoinRejection> {
+        if !self.active {
+            return Err(JoinRejection::GameOver);
+        }
+        // Every join takes a spawn point, so a lone bomberman with none left
+        // means the map never had any.
+        if self.spawns.is_empty() && self.bombermen.len() == 1 {
+            return Err(JoinRejection::NoSpawnPoints);
+        }
+        if self.bombermen.len() >= MAX_PLAYERS || self.spawns.is_empty() {
+            return Err(JoinRejection::Full);
+        }
+        let coords = self.spawns.remove(0);
+        self.updated = self.clock.now();
+        self.bombermen.push(Bomberman::new(coords, self.updated));
+        self.record(Event::Joined {
+            player: self.bombermen.len() - 1,
+            coords,
+        });
+        Ok(Player {
+            index: self.bombermen.len() - 1,
+            token: self.bombermen[self.bombermen.len() - 1].token,
+        })
     }
-    pub fn surrounding(&mut self) -> Surroundings {
-        self.blast();
-        let h_min = cmp::max(0, self.bomberman.0 as i8 - self.surrounding_size as i8) as usize;
-        let h_max = cmp::min(
-            self.height as i8 - 1,
-            self.bomberman.0 as i8 + self.surrounding_size as i8,
-        ) as usize;
-        let w_min = cmp::max(0, self.bomberman.1 as i8 - self.surrounding_size as i8) as usize;
-        let w_max = cmp::min(
-            self.width as i8 - 1,
-            self.bomberman.1 as i8 + self.surrounding_size as i8,
-        ) as usize;
+    // The bomberman placed by the template, whose token is handed to the creator.
+    pub fn creator(&self) -> Player {
+        Player {
+            index: 0,
+            token: self.bombermen[0].token,
+        }
+    }
+    pub fn player_by_token(&self, token: Uuid) -> Option<usize> {
+        self.bombermen.iter().position(|b| b.token == token)
+    }
+    pub fn last_update(&mut self) -> DateTime<Utc> {
+        self.tick();
+        self.updated
+    }
+    // Every bomb the player has ticking, the one due first leading.
+    pub fn bomb_status(&mut self, player: usize) -> Vec<BombStatus> {
+        self.tick();
+        let now = self.clock.now();
+        self.bombs
+            .iter()
+            .filter(|b| b.owner == player)
+            .map(|b| {
+                let fuse = FUSE_MILLIS - (now - b.planted).num_milliseconds();
+                BombStatus {
+                    coords: b.coords,
+                    planted: (b.planted - self.started).num_milliseconds(),
+                    fuse,
+                    turns: match self.mode {
+                        Mode::RealTime => None,
+                        Mode::Turns => Some(((fuse + TURN_MILLIS - 1) / TURN_MILLIS) as u64),
+                    },
+                    area: self.blast_area(b.coords, b.radius),
+                }
+            })
+            .collect()
+    }
+    pub fn surrounding(&mut self, player: usize) -> Surroundings {
+        self.tick();
+        let centre = self.bombermen[player].coords;
+        let size = self.surrounding_size;
+        let h_min = centre.0.saturating_sub(size);
+        let h_max = cmp::min(self.height - 1, centre.0 + size);
+        let w_min = centre.1.saturating_sub(size);
+        let w_max = cmp::min(self.width - 1, centre.1 + size);
         let mut result = Surroundings {
             bricks: vec![],
             wall: vec![],
-            bombermans: vec![self.bomberman],
+            bombermans: vec![centre],
             ghosts: vec![],
             gates: vec![],
+            power_ups: vec![],
+            bombs: vec![],
         };
+        for (i, b) in self.bombermen.iter().enumerate() {
+            let (h, w) = b.coords;
+            if i != player
+                && b.outcome.is_none()
+                && (h_min..=h_max).contains(&h)
+                && (w_min..=w_max).contains(&w)
+            {
+                result.bombermans.push(b.coords);
+            }
+        }
+        for b in &self.bombs {
+            let (h, w) = b.coords;
+            if (h_min..=h_max).contains(&h) && (w_min..=w_max).contains(&w) {
+                result.bombs.push(b.coords);
+            }
+        }
         for h in h_min..=h_max {
             for w in w_min..=w_max {
                 let coord = (h, w);
                 match self.landscape.get(&coord) {
-                    Some(Cell::Brick) => result.bricks.push(coord),
+                    Some(Cell::Brick) | Some(Cell::HiddenGate) | Some(Cell::HiddenPowerUp(_)) => {
+                        result.bricks.push(coord)
+                    }
                     Some(Cell::Wall) => result.wall.push(coord),
                     Some(Cell::OpenGate) => result.gates.push(coord),
                     Some(Cell::Ghost) => result.ghosts.push(coord),
+                    Some(Cell::PowerUp(p)) => result.power_ups.push((coord, *p)),
                     _ => {}
                 }
             }
@@ -79,102 +518,513 @@ impl Game {
         result
     }
 
-    pub fn bomb_surrounding(&mut self) -> Option<Surroundings> {
-        self.blast();
-        match self.bomb {
-            None => None,
-            Some((_, c)) => {
-                let h_min = cmp::max(0, c.0 as i8 - 1) as usize;
-                let h_max = cmp::min(self.height as i8 - 1, c.0 as i8 + 1) as usize;
-                let w_min = cmp::max(0, c.1 as i8 - 1) as usize;
-                let w_max = cmp::min(self.width as i8 - 1, c.1 as i8 + 1 as i8) as usize;
-                let mut result = Surroundings {
-                    bricks: vec![],
-                    wall: vec![],
-                    bombermans: vec![self.bomberman],
-                    ghosts: vec![],
-                    gates: vec![],
+    // The whole map in template chars. The creator is drawn as M and the other
+    // living players as P; bombs show as BOMB, or BOMBER_ON_BOMB when their
+    // bomberman hasn't stepped off yet.
+    pub fn board(&mut self, show_hidden: bool) -> Vec<String> {
+        self.tick();
+        let mut rows: Vec<Vec<char>> = (0..self.height)
+            .map(|h| {
+                (0..self.width)
+                    .map(|w| match self.landscape.get(&(h, w)) {
+                        Some(cell) => templates::char_from_cell(cell, show_hidden),
+                        None => ' ',
+                    })
+                    .collect()
+            })
+            .collect();
+        for ghost in &self.ghosts {
+            rows[ghost.coords.0][ghost.coords.1] = templates::char_from_ghost(ghost.behaviour);
+        }
+        for bomb in &self.bombs {
+            rows[bomb.coords.0][bomb.coords.1] = templates::BOMB;
+        }
+        for (i, b) in self.bombermen.iter().enumerate() {
+            if b.outcome.is_none() {
+                let (h, w) = b.coords;
+                rows[h][w] = match rows[h][w] {
+                    templates::BOMB => templates::BOMBER_ON_BOMB,
+                    _ if i == 0 => 'M',
+                    _ => 'P',
                 };
-                for h in h_min..=h_max {
-                    for w in w_min..=w_max {
-                        let coord = (h, w);
-                        match self.landscape.get(&coord) {
-                            Some(Cell::Brick) => result.bricks.push(coord),
-                            Some(Cell::Wall) => result.wall.push(coord),
-                            _ => {}
-                        }
+            }
+        }
+        rows.into_iter().map(|r| r.into_iter().collect()).collect()
+    }
+
+    // What the player's next bomb will reach, along with every bomb it sets off.
+    pub fn bomb_surrounding(&mut self, player: usize) -> Option<Surroundings> {
+        self.tick();
+        self.bomb_of(player).map(|bomb| {
+            let mut result = Surroundings {
+                bricks: vec![],
+                wall: vec![],
+                bombermans: vec![self.bombermen[player].coords],
+                ghosts: vec![],
+                gates: vec![],
+                power_ups: vec![],
+                bombs: vec![],
+            };
+            let mut ticking: Vec<Bomb> =
+                self.bombs.iter().filter(|b| **b != bomb).cloned().collect();
+            let mut area: Vec<Coord> = vec![];
+            for (bomb, cells, _) in self.chain_reaction(bomb, &mut ticking) {
+                result.bombs.push(bomb.coords);
+                for coord in cells {
+                    if !area.contains(&coord) {
+                        area.push(coord);
                     }
                 }
-                Some(result)
             }
+            for coord in area {
+                match self.landscape.get(&coord) {
+                    Some(Cell::Brick) | Some(Cell::HiddenGate) | Some(Cell::HiddenPowerUp(_)) => {
+                        result.bricks.push(coord)
+                    }
+                    Some(Cell::Wall) => result.wall.push(coord),
+                    Some(Cell::Ghost) => result.ghosts.push(coord),
+                    _ => {}
+                }
+            }
+            result
+        })
+    }
+
+    pub fn outcome(&mut self, player: usize) -> Option<Outcome> {
+        self.tick();
+        self.bombermen[player].outcome
+    }
+
+    pub fn game_status(&mut self, player: usize) -> GameStatus {
+        self.tick();
+        let bomberman = &self.bombermen[player];
+        let status = match bomberman.outcome {
+            None => Status::Running,
+            Some(Outcome::ReachedGate) => Status::Won,
+            Some(_) => Status::Lost,
+        };
+        let until = bomberman.finished.unwrap_or_else(|| self.clock.now());
+        GameStatus {
+            status,
+            outcome: bomberman.outcome,
+            elapsed: until.timestamp_millis() - self.started.timestamp_millis(),
+            turn: match self.mode {
+                Mode::RealTime => None,
+                Mode::Turns => Some(self.turn),
+            },
         }
     }
 
-    pub fn bomberman_up(&mut self) {
-        self.mv((-1, 0));
-        self.blast();
+    pub fn next_turn(&mut self) {
+        if self.mode != Mode::Turns || !self.active {
+            return;
+        }
+        // At the end of time the game stops taking turns.
+        if let Some(now) = self
+            .clock
+            .now()
+            .checked_add_signed(Duration::milliseconds(TURN_MILLIS))
+        {
+            self.clock = Clock::Manual(now);
+            self.turn += 1;
+            self.record(Event::Turn { turn: self.turn });
+        }
     }
-    pub fn bomberman_down(&mut self) {
-        self.mv((1, 0));
-        self.blast();
+
+    // Runs a whole command chain as one turn.
+    pub fn execute(
+        &mut self,
+        player: usize,
+        commands: &[Commands],
+        capabilities: &Capabilities,
+    ) -> Vec<Reply> {
+        self.next_turn();
+        commands
+            .iter()
+            .map(|command| match command {
+                _ if !capabilities.allow(command) => Reply::Skipped,
+                Commands::MoveBomberman { direction } => {
+                    Reply::Done(self.bomberman_move(player, *direction))
+                }
+                Commands::FetchSurrounding => Reply::Surrounding(self.surrounding(player)),
+                Commands::PlantBomb => Reply::Done(self.plant_bomb(player)),
+                Commands::FetchBombStatus => Reply::BombStatus(self.bomb_status(player)),
+                Commands::FetchBombSurrounding => {
+                    Reply::BombSurrounding(self.bomb_surrounding(player))
+                }
+                Commands::FetchGameStatus => Reply::GameStatus(self.game_status(player)),
+            })
+            .collect()
     }
-    pub fn bomberman_left(&mut self) {
-        self.mv((0, -1));
-        self.blast();
+
+    #[cfg(test)]
+    pub fn advance(&mut self, by: Duration) {
+        self.clock = Clock::Manual(self.clock.now() + by);
+    }
+
+    pub fn log(&self) -> &[LogEntry] {
+        &self.log
     }
-    pub fn bomberman_right(&mut self) {
-        self.mv((0, 1));
+    pub fn restore_log(&mut self, log: Vec<LogEntry>) {
+        self.log = log;
+    }
+    // The log as players may see it: the template in the Created entry has its
+    // hidden cells masked unless show_hidden is set.
+    pub fn published_log(&self, show_hidden: bool) -> Vec<LogEntry> {
+        let mut log = self.log.clone();
+        if let Some(LogEntry {
+            event: Event::Created { template, .. },
+            ..
+        }) = log.first_mut().filter(|_| !show_hidden)
+        {
+            *template = templates::mask(template);
+        }
+        log
+    }
+    fn record(&mut self, event: Event) {
+        self.log.push(LogEntry {
+            at: self.clock.now(),
+            event,
+        });
+    }
+
+    pub fn bomberman_move(&mut self, player: usize, direction: Direction) -> Result<(), Rejection> {
+        self.tick();
+        self.mv(player, direction)
+    }
+    fn mv(&mut self, player: usize, direction: Direction) -> Result<(), Rejection> {
+        let now = self.clock.now();
+        let bomberman = &self.bombermen[player];
+        let new = Game::add(bomberman.coords, direction.offset());
+        let rejected = if bomberman.outcome.is_some() {
+            Some(Rejection::GameOver)
+        } else if now.timestamp_millis() - bomberman.moved.timestamp_millis()
+            <= bomberman.throttle()
+        {
+            Some(Rejection::Throttled)
+        } else if self.bomb_at(new) {
+            Some(Rejection::BlockedByBomb)
+        } else {
+            match self.landscape.get(&new) {
+                Some(Cell::Empty)
+                | Some(Cell::OpenGate)
+                | Some(Cell::Ghost)
+                | Some(Cell::PowerUp(_)) => None,
+                _ => Some(Rejection::Blocked),
+            }
+        };
+        if let Some(reason) = rejected {
+            self.record(Event::MoveRejected {
+                player,
+                direction,
+                reason,
+            });
+            return Err(reason);
+        }
+        let outcome = match self.landscape.get(&new) {
+            Some(Cell::OpenGate) => Some(Outcome::ReachedGate),
+            Some(Cell::Ghost) => Some(Outcome::KilledByGhost),
+            _ => None,
+        };
+        let bomberman = &mut self.bombermen[player];
+        bomberman.coords = new;
+        bomberman.moved = now;
+        self.updated = now;
+        self.record(Event::Moved {
+            player,
+            direction,
+            to: new,
+        });
+        if let Some(Cell::PowerUp(power_up)) = self.landscape.get(&new).cloned() {
+            self.landscape.insert(new, Cell::Empty);
+            self.bombermen[player].collect(power_up);
+            self.record(Event::PowerUpCollected { player, power_up });
+        }
+        if let Some(outcome) = outcome {
+            self.finish(player, outcome);
+        }
+        Ok(())
+    }
+    pub fn plant_bomb(&mut self, player: usize) -> Result<(), Rejection> {
+        let now = self.clock.now();
+        self.tick();
+        if self.bombermen[player].outcome.is_some() {
+            return Err(Rejection::GameOver);
+        }
+        let bomberman = &self.bombermen[player];
+        let capacity = self.bomb_capacity.saturating_add(bomberman.extra_bombs);
+        if self.bombs.iter().filter(|b| b.owner == player).count() >= capacity {
+            return Err(Rejection::NoBombsLeft);
+        }
+        let coords = bomberman.coords;
+        if self.bomb_at(coords) {
+            return Err(Rejection::BombInPlace);
+        }
+        let radius = self.blast_radius.saturating_add(bomberman.extra_flame);
+        self.bombs.push(Bomb {
+            owner: player,
+            planted: now,
+            coords,
+            radius,
+        });
+        self.updated = now;
+        self.record(Event::BombPlanted { player, coords });
+        Ok(())
+    }
+    fn bomb_of(&self, player: usize) -> Option<Bomb> {
+        self.bombs.iter().find(|b| b.owner == player).cloned()
+    }
+    fn tick(&mut self) {
+        self.haunt();
         self.blast();
     }
-    fn mv(&mut self, offset: (i8, i8)) {
-        let now = Utc::now().timestamp_millis();
-        if now - self.updated.timestamp_millis() > 200 && self.active {
-            let new = Game::add(self.bomberman, offset);
-            if let Some(c) = self.landscape.get(&new) {
-                match c {
-                    Cell::Empty => {
-                        self.bomberman = new;
-                        self.updated = Utc::now();
-                    }
-                    Cell::OpenGate | Cell::Ghost => {
-                        self.bomberman = new;
-                        self.updated = Utc::now();
-                        self.active = false;
-                    }
-                    _ => {}
+    fn haunt(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.timestamp_millis() - self.ghosts_moved.timestamp_millis();
+        let steps = elapsed / self.ghost_step;
+        if steps == 0 {
+            return;
+        }
+        if steps > MAX_GHOST_STEPS {
+            self.ghosts_moved = now;
+        } else {
+            self.ghosts_moved = self.ghosts_moved + Duration::milliseconds(steps * self.ghost_step);
+        }
+        let mut stepped = false;
+        for _ in 0..cmp::min(steps, MAX_GHOST_STEPS) {
+            if !self.active || self.ghosts.is_empty() {
+                break;
+            }
+            for i in 0..self.ghosts.len() {
+                self.step_ghost(i);
+            }
+            self.updated = now;
+            stepped = true;
+        }
+        if stepped {
+            let ghosts = self.ghosts.iter().map(|g| g.coords).collect();
+            self.record(Event::GhostsMoved { ghosts });
+        }
+    }
+    fn step_ghost(&mut self, i: usize) {
+        let ghost = self.ghosts[i].clone();
+        let free: Vec<(i8, i8)> = DIRECTIONS
+            .iter()
+            .filter(|d| self.ghost_can_enter(Game::add(ghost.coords, **d)))
+            .cloned()
+            .collect();
+        if free.is_empty() {
+            return;
+        }
+        let heading = match ghost.behaviour {
+            GhostBehaviour::Wander => *free.choose(&mut self.rng).unwrap(),
+            GhostBehaviour::Corridor => {
+                let back = (-ghost.heading.0, -ghost.heading.1);
+                let ahead: Vec<(i8, i8)> = free.iter().filter(|d| **d != back).cloned().collect();
+                if free.contains(&ghost.heading) && ahead.len() == 1 {
+                    ghost.heading
+                } else if ahead.is_empty() {
+                    back
+                } else {
+                    *ahead.choose(&mut self.rng).unwrap()
                 }
             }
+            GhostBehaviour::Chase => match self.towards(ghost.coords, &self.alive()) {
+                Some(d) => d,
+                None => *free.choose(&mut self.rng).unwrap(),
+            },
         };
+        let target = Game::add(ghost.coords, heading);
+        self.landscape.insert(ghost.coords, Cell::Empty);
+        self.landscape.insert(target, Cell::Ghost);
+        self.ghosts[i].coords = target;
+        self.ghosts[i].heading = heading;
+        for player in 0..self.bombermen.len() {
+            if self.bombermen[player].coords == target {
+                self.finish(player, Outcome::KilledByGhost);
+            }
+        }
     }
-    pub fn plant_bomb(&mut self) {
-        let now = Utc::now();
-        self.blast();
-        match self.bomb {
-            None => self.bomb = Some((now, self.bomberman)),
-            Some(_) => (),
+    fn ghost_can_enter(&self, coords: Coord) -> bool {
+        self.landscape.get(&coords) == Some(&Cell::Empty) && !self.bomb_at(coords)
+    }
+    // Bombs block the way like bricks. Nobody can walk onto one, but whoever
+    // stands on a bomb, typically the one who just planted it, may step off.
+    fn bomb_at(&self, coords: Coord) -> bool {
+        self.bombs.iter().any(|b| b.coords == coords)
+    }
+    fn alive(&self) -> Vec<Coord> {
+        self.bombermen
+            .iter()
+            .filter(|b| b.outcome.is_none())
+            .map(|b| b.coords)
+            .collect()
+    }
+    // First step of a shortest path over cells a ghost may enter, towards the
+    // nearest of the targets.
+    fn towards(&self, from: Coord, targets: &[Coord]) -> Option<(i8, i8)> {
+        let mut first: HashMap<Coord, (i8, i8)> = HashMap::new();
+        let mut queue: VecDeque<Coord> = VecDeque::new();
+        for d in DIRECTIONS.iter() {
+            let next = Game::add(from, *d);
+            if self.ghost_can_enter(next) {
+                first.insert(next, *d);
+                queue.push_back(next);
+            }
         }
+        while let Some(current) = queue.pop_front() {
+            let d = first[&current];
+            if targets.contains(&current) {
+                return Some(d);
+            }
+            for o in DIRECTIONS.iter() {
+                let next = Game::add(current, *o);
+                if next != from && !first.contains_key(&next) && self.ghost_can_enter(next) {
+                    first.insert(next, d);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
     }
+    // Bombs go off in planting order. Each one sets off every bomb its flames
+    // reach, and the whole chain burns at once on the landscape it found:
+    // nothing is demolished until every bomb of the chain has exploded.
     fn blast(&mut self) {
-        let now = Utc::now();
-        match self.bomb {
-            None => {}
-            Some((planted, coords)) => {
-                if now.timestamp() - planted.timestamp() >= 4 {
-                    self.demolish(Game::add(coords, (0, 1)));
-                    self.demolish(Game::add(coords, (1, 0)));
-                    self.demolish(Game::add(coords, (0, -1)));
-                    self.demolish(Game::add(coords, (-1, 0)));
-                    self.bomb = None;
+        let now = self.clock.now();
+        while let Some(i) = self
+            .bombs
+            .iter()
+            .position(|b| (now - b.planted).num_milliseconds() >= FUSE_MILLIS)
+        {
+            self.updated = now;
+            let first = self.bombs.remove(i);
+            let mut ticking = std::mem::take(&mut self.bombs);
+            let chain = self.chain_reaction(first, &mut ticking);
+            self.bombs = ticking;
+            let blasts: Vec<(Event, Vec<Coord>)> = chain
+                .into_iter()
+                .map(|(bomb, area, chained)| {
+                    let destroyed = area
+                        .iter()
+                        .filter(|c| {
+                            matches!(
+                                self.landscape.get(c),
+                                Some(Cell::Brick)
+                                    | Some(Cell::Ghost)
+                                    | Some(Cell::HiddenGate)
+                                    | Some(Cell::HiddenPowerUp(_))
+                            )
+                        })
+                        .cloned()
+                        .collect();
+                    let event = Event::Blast {
+                        coords: bomb.coords,
+                        destroyed,
+                        area: area.clone(),
+                        chained,
+                    };
+                    (event, area)
+                })
+                .collect();
+            for (event, area) in blasts {
+                self.record(event);
+                for coords in area {
+                    self.demolish(coords);
+                    for player in 0..self.bombermen.len() {
+                        if self.bombermen[player].coords == coords {
+                            self.finish(player, Outcome::KilledByBomb);
+                        }
+                    }
                 }
             }
         }
     }
+    // The bombs set off by the first one, breadth first: each explosion with
+    // the cells it reaches and the bombs it sets off, which are taken out of
+    // the ticking ones.
+    fn chain_reaction(
+        &self,
+        first: Bomb,
+        ticking: &mut Vec<Bomb>,
+    ) -> Vec<(Bomb, Vec<Coord>, Vec<Coord>)> {
+        let mut result = vec![];
+        let mut queue = VecDeque::from(vec![first]);
+        while let Some(bomb) = queue.pop_front() {
+            let area = self.blast_area(bomb.coords, bomb.radius);
+            let (hit, rest): (Vec<Bomb>, Vec<Bomb>) =
+                ticking.iter().partition(|b| area.contains(&b.coords));
+            *ticking = rest;
+            let chained = hit.iter().map(|b| b.coords).collect();
+            queue.extend(hit);
+            result.push((bomb, area, chained));
+        }
+        result
+    }
+    // Ends the game for one bomberman. The whole game is over once somebody
+    // reaches the gate or nobody is left alive.
+    fn finish(&mut self, player: usize, outcome: Outcome) {
+        if !self.active || self.bombermen[player].outcome.is_some() {
+            return;
+        }
+        let now = self.clock.now();
+        self.updated = now;
+        self.bombermen[player].outcome = Some(outcome);
+        self.bombermen[player].finished = Some(now);
+        self.record(Event::Finished { player, outcome });
+        if outcome == Outcome::ReachedGate {
+            for rival in 0..self.bombermen.len() {
+                if self.bombermen[rival].outcome.is_none() {
+                    self.bombermen[rival].outcome = Some(Outcome::RivalReachedGate);
+                    self.bombermen[rival].finished = Some(now);
+                    self.record(Event::Finished {
+                        player: rival,
+                        outcome: Outcome::RivalReachedGate,
+                    });
+                }
+            }
+        }
+        self.active = self.bombermen.iter().any(|b| b.outcome.is_none());
+    }
+    // Cells reached by an explosion: rays travel outwards in every direction,
+    // pass through ghosts, stop at the first brick (hidden gates included) and
+    // end on a wall.
+    fn blast_area(&self, origin: Coord, radius: usize) -> Vec<Coord> {
+        let mut result = vec![origin];
+        for d in DIRECTIONS.iter() {
+            let mut current = origin;
+            for _ in 0..radius {
+                current = Game::add(current, *d);
+                match self.landscape.get(&current) {
+                    None => break,
+                    Some(Cell::Wall)
+                    | Some(Cell::Brick)
+                    | Some(Cell::HiddenGate)
+                    | Some(Cell::HiddenPowerUp(_)) => {
+                        result.push(current);
+                        break;
+                    }
+                    Some(_) => result.push(current),
+                }
+            }
+        }
+        result
+    }
     fn demolish(&mut self, coords: (usize, usize)) {
         if let Some(c) = self.landscape.get_mut(&coords) {
             match c {
                 Cell::Brick | Cell::Ghost => {
                     *c = Cell::Empty;
-                    self.updated = Utc::now();
+                    self.ghosts.retain(|g| g.coords != coords);
+                    self.updated = self.clock.now();
+                }
+                Cell::HiddenGate => {
+                    *c = Cell::OpenGate;
+                    self.updated = self.clock.now();
+                }
+                Cell::HiddenPowerUp(power_up) => {
+                    *c = Cell::PowerUp(*power_up);
+                    self.updated = self.clock.now();
                 }
                 _ => {}
             }
@@ -188,6 +1038,35 @@ impl Game {
     }
 }
 
+impl Bomberman {
+    fn new(coords: Coord, now: DateTime<Utc>) -> Bomberman {
+        Bomberman {
+            coords,
+            token: Uuid::new_v4(),
+            moved: now,
+            outcome: None,
+            finished: None,
+            extra_bombs: 0,
+            extra_flame: 0,
+            speed: 0,
+        }
+    }
+    // Each speed power-up shortens the pause between moves, down to a floor.
+    fn throttle(&self) -> i64 {
+        cmp::max(
+            MIN_THROTTLE_MILLIS,
+            THROTTLE_MILLIS - SPEED_STEP_MILLIS * self.speed as i64,
+        )
+    }
+    fn collect(&mut self, power_up: PowerUp) {
+        match power_up {
+            PowerUp::ExtraBomb => self.extra_bombs += 1,
+            PowerUp::Flame => self.extra_flame += 1,
+            PowerUp::Speed => self.speed += 1,
+        }
+    }
+}
+
 type Template = &'static [&'static str];
 
 pub mod templates {
@@ -196,16 +1075,124 @@ pub mod templates {
     pub fn by_name(name: &str) -> Option<Template> {
         match name.to_lowercase().as_str() {
             "small_1" => Some(SMALL_1),
+            "small_2" => Some(SMALL_2),
             "wide_1" => Some(WIDE_1),
+            "arena_1" => Some(ARENA_1),
             _ => None,
         }
     }
 
     pub fn random() -> Template {
         let mut rng = rand::thread_rng();
-        let mut ts: Vec<Template> = vec![SMALL_1, WIDE_1];
+        let mut ts: Vec<Template> = vec![SMALL_1, SMALL_2, WIDE_1];
         ts.shuffle(&mut rng);
-        ts.get(0).unwrap()
+        ts.first().unwrap()
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default)]
+    pub struct Generation {
+        pub width: usize,
+        pub height: usize,
+        pub seed: Option<u64>,
+        pub bricks: f64,
+        pub ghosts: usize,
+        pub power_ups: f64,
+    }
+
+    impl Default for Generation {
+        fn default() -> Generation {
+            Generation {
+                width: 15,
+                height: 15,
+                seed: None,
+                bricks: 0.3,
+                ghosts: 2,
+                power_ups: 0.0,
+            }
+        }
+    }
+
+    pub const GENERATED_SIZES: std::ops::RangeInclusive<usize> = 7..=61;
+
+    // Classic layout: outer walls, a pillar on every even cell, bricks spread
+    // with the given density, a gate (hidden when it lands on a brick) far
+    // from the bomberman and ghosts kept away from the spawn corner. Pillars
+    // never cut the open cells apart, so the gate is always reachable.
+    pub fn generate(g: &Generation, seed: u64) -> Result<Vec<String>, String> {
+        if !GENERATED_SIZES.contains(&g.width) || !GENERATED_SIZES.contains(&g.height) {
+            return Err(format!(
+                "Width and height must be within {} and {}",
+                GENERATED_SIZES.start(),
+                GENERATED_SIZES.end()
+            ));
+        }
+        if !(0.0..=1.0).contains(&g.bricks) {
+            return Err(String::from("Brick density must be within 0 and 1"));
+        }
+        if !(0.0..=1.0).contains(&g.power_ups) {
+            return Err(String::from("Power-up density must be within 0 and 1"));
+        }
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let mut rows = vec![vec![' '; g.width]; g.height];
+        // One spawn in every corner, on the last odd row and column so it
+        // never lands on a pillar.
+        let odd = |n: usize| if n % 2 == 1 { n } else { n - 1 };
+        let (last_h, last_w) = (odd(g.height - 2), odd(g.width - 2));
+        let spawns = [(1, 1), (1, last_w), (last_h, 1), (last_h, last_w)];
+        let distance = |(h, w): Coord| {
+            spawns
+                .iter()
+                .map(|(sh, sw)| sh.abs_diff(h) + sw.abs_diff(w))
+                .min()
+                .unwrap()
+        };
+        let mut open = vec![];
+        for (h, row) in rows.iter_mut().enumerate() {
+            for (w, c) in row.iter_mut().enumerate() {
+                let border = h == 0 || w == 0 || h == g.height - 1 || w == g.width - 1;
+                if border || (h % 2 == 0 && w % 2 == 0) {
+                    *c = 'X';
+                } else if distance((h, w)) > 1 {
+                    if rng.gen_bool(g.bricks) {
+                        *c = 'B';
+                    }
+                    open.push((h, w));
+                }
+            }
+        }
+        rows[1][1] = 'M';
+        for (h, w) in &spawns[1..] {
+            rows[*h][*w] = 'P';
+        }
+        let far = (g.width + g.height) / 2;
+        let gates: Vec<Coord> = open.iter().filter(|(h, w)| h + w >= far).cloned().collect();
+        let gate = *gates.choose(&mut rng).unwrap();
+        rows[gate.0][gate.1] = if rows[gate.0][gate.1] == 'B' {
+            'H'
+        } else {
+            'O'
+        };
+        let mut lairs: Vec<Coord> = open
+            .iter()
+            .filter(|c| distance(**c) >= 4 && rows[c.0][c.1] == ' ')
+            .cloned()
+            .collect();
+        lairs.shuffle(&mut rng);
+        for (h, w) in lairs.into_iter().take(g.ghosts) {
+            rows[h][w] = *['G', 'C', 'S'].choose(&mut rng).unwrap();
+        }
+        // Rolled last and only when asked for, so older seeds keep their maps.
+        if g.power_ups > 0.0 {
+            for c in rows.iter_mut().flatten().filter(|c| **c == 'B') {
+                if rng.gen_bool(g.power_ups) {
+                    *c = POWER_UPS.choose(&mut rng).unwrap().1;
+                }
+            }
+        }
+        let rows: Vec<String> = rows.into_iter().map(|r| r.into_iter().collect()).collect();
+        validate(&rows)?;
+        Ok(rows)
     }
 
     #[rustfmt::skip]
@@ -226,6 +1213,24 @@ pub mod templates {
         "X            OX",
         "XXXXXXXXXXXXXXX"];
 
+    #[rustfmt::skip]
+    pub const SMALL_2: Template = &[
+        "XXXXXXXXXXXXXXX",
+        "XM  B  B   B  X",
+        "X XBX X XBX X X",
+        "X B   B B   B X",
+        "XBX XBX X XBXBX",
+        "X   B   B B   X",
+        "X XBX XBX X X X",
+        "XB  G   B   BBX",
+        "X X XBX XBXBX X",
+        "X B B   B   B X",
+        "XBX X XBX X XBX",
+        "X   B B   G   X",
+        "X XBX X XBX XBX",
+        "X B   B   B BHX",
+        "XXXXXXXXXXXXXXX"];
+
     #[rustfmt::skip]
     pub const WIDE_1: Template = &[
         "XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX",
@@ -244,9 +1249,95 @@ pub mod templates {
         "X                                                          X",
         "XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX"];
 
+    #[rustfmt::skip]
+    pub const ARENA_1: Template = &[
+        "XXXXXXXXXXXXXXXXXXXXX",
+        "XM  BBB   G   BBB  PX",
+        "X X XBX X X X XBX X X",
+        "X B  B  B   B  B  B X",
+        "XBXBX X XBXBX X XBXBX",
+        "X   B  BB   BB  B   X",
+        "X X X XBX X XBX X X X",
+        "XB B  B   O   B  B BX",
+        "X X X XBX X XBX X X X",
+        "X   B  BB   BB  B   X",
+        "XBXBX X XBXBX X XBXBX",
+        "X B  B  B   B  B  B X",
+        "X X XBX X X X XBX X X",
+        "XP  BBB   G   BBB  PX",
+        "XXXXXXXXXXXXXXXXXXXXX"];
+
+    // Moves step through coordinates as i8, so no side may be longer.
+    pub const MAX_SIZE: usize = i8::MAX as usize;
+
+    // Checks a template before a game is built from it: rectangular, enclosed
+    // by walls, exactly one bomberman and a gate the bomberman can reach once
+    // bricks are blasted away.
+    pub fn validate<S: AsRef<str>>(template: &[S]) -> Result<(), String> {
+        let rows: Vec<Vec<char>> = template
+            .iter()
+            .map(|r| r.as_ref().chars().collect())
+            .collect();
+        let width = rows.first().map(|r| r.len()).unwrap_or(0);
+        if rows.len() < 3 || width < 3 {
+            return Err(String::from("Template must be at least 3x3"));
+        }
+        if rows.len() > MAX_SIZE || width > MAX_SIZE {
+            return Err(format!(
+                "Template must be at most {}x{}",
+                MAX_SIZE, MAX_SIZE
+            ));
+        }
+        if let Some(h) = rows.iter().position(|r| r.len() != width) {
+            return Err(format!("Row {} is not {} cells wide", h, width));
+        }
+        let mut bombers = vec![];
+        let mut gates = vec![];
+        for (h, row) in rows.iter().enumerate() {
+            for (w, c) in row.iter().enumerate() {
+                let border = h == 0 || w == 0 || h == rows.len() - 1 || w == width - 1;
+                match cell_from_char(*c) {
+                    LandscapeFromChar::Unknown => {
+                        return Err(format!("Unknown char '{}' at ({}, {})", c, h, w))
+                    }
+                    LandscapeFromChar::Land { cell: Cell::Wall } => {}
+                    _ if border => return Err(format!("Border at ({}, {}) is not a wall", h, w)),
+                    LandscapeFromChar::Bomber => bombers.push((h, w)),
+                    LandscapeFromChar::Land {
+                        cell: Cell::OpenGate,
+                    }
+                    | LandscapeFromChar::Land {
+                        cell: Cell::HiddenGate,
+                    } => gates.push((h, w)),
+                    _ => {}
+                }
+            }
+        }
+        if bombers.len() != 1 {
+            return Err(format!("Expected one bomberman, found {}", bombers.len()));
+        }
+        let mut seen = vec![bombers[0]];
+        let mut queue = VecDeque::from(vec![bombers[0]]);
+        while let Some(current) = queue.pop_front() {
+            if gates.contains(&current) {
+                return Ok(());
+            }
+            for d in DIRECTIONS.iter() {
+                let (h, w) = Game::add(current, *d);
+                if rows[h][w] != 'X' && !seen.contains(&(h, w)) {
+                    seen.push((h, w));
+                    queue.push_back((h, w));
+                }
+            }
+        }
+        Err(String::from("No gate is reachable from the bomberman"))
+    }
+
     pub enum LandscapeFromChar {
         Land { cell: Cell },
         Bomber,
+        Spawn,
+        Ghost { behaviour: GhostBehaviour },
         Unknown,
     }
 
@@ -262,18 +1353,96 @@ pub mod templates {
                 cell: Cell::HiddenGate,
             },
             'M' => LandscapeFromChar::Bomber,
-            'G' => LandscapeFromChar::Land { cell: Cell::Ghost },
-            _ => LandscapeFromChar::Unknown,
+            'P' => LandscapeFromChar::Spawn,
+            'G' => LandscapeFromChar::Ghost {
+                behaviour: GhostBehaviour::Wander,
+            },
+            'C' => LandscapeFromChar::Ghost {
+                behaviour: GhostBehaviour::Corridor,
+            },
+            'S' => LandscapeFromChar::Ghost {
+                behaviour: GhostBehaviour::Chase,
+            },
+            _ => match POWER_UPS.iter().find(|p| p.1 == c || p.2 == c) {
+                Some((power_up, hidden, _)) if *hidden == c => LandscapeFromChar::Land {
+                    cell: Cell::HiddenPowerUp(*power_up),
+                },
+                Some((power_up, _, _)) => LandscapeFromChar::Land {
+                    cell: Cell::PowerUp(*power_up),
+                },
+                None => LandscapeFromChar::Unknown,
+            },
+        }
+    }
+
+    // Each power-up with its char under a brick and once revealed.
+    pub const POWER_UPS: [(PowerUp, char, char); 3] = [
+        (PowerUp::ExtraBomb, 'b', '+'),
+        (PowerUp::Flame, 'f', '!'),
+        (PowerUp::Speed, 's', '>'),
+    ];
+
+    fn power_up_char(power_up: PowerUp, hidden: bool) -> char {
+        let (_, under_brick, shown) = POWER_UPS.iter().find(|p| p.0 == power_up).unwrap();
+        if hidden {
+            *under_brick
+        } else {
+            *shown
+        }
+    }
+
+    // Board-only symbols, never accepted in templates.
+    pub const BOMB: char = '*';
+    pub const BOMBER_ON_BOMB: char = '@';
+
+    // The reverse of cell_from_char. Hidden gates and power-ups pass for bricks
+    // unless asked.
+    pub fn char_from_cell(cell: &Cell, show_hidden: bool) -> char {
+        match cell {
+            Cell::Empty => ' ',
+            Cell::Wall => 'X',
+            Cell::Brick => 'B',
+            Cell::OpenGate => 'O',
+            Cell::HiddenGate if show_hidden => 'H',
+            Cell::HiddenGate => 'B',
+            Cell::Ghost => 'G',
+            Cell::HiddenPowerUp(p) if show_hidden => power_up_char(*p, true),
+            Cell::HiddenPowerUp(_) => 'B',
+            Cell::PowerUp(p) => power_up_char(*p, false),
+        }
+    }
+
+    // Shows hidden gates and power-ups as the bricks they lie under.
+    pub fn mask(template: &[String]) -> Vec<String> {
+        template
+            .iter()
+            .map(|row| {
+                row.chars()
+                    .map(|c| match cell_from_char(c) {
+                        LandscapeFromChar::Land { cell } => char_from_cell(&cell, false),
+                        _ => c,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn char_from_ghost(behaviour: GhostBehaviour) -> char {
+        match behaviour {
+            GhostBehaviour::Wander => 'G',
+            GhostBehaviour::Corridor => 'C',
+            GhostBehaviour::Chase => 'S',
         }
     }
 }
 
-pub fn new(template: &[&str]) -> Game {
+pub fn new<S: AsRef<str>>(template: &[S], settings: &Settings) -> Game {
     let mut m: HashMap<Coord, Cell> = HashMap::new();
     let mut width = 0;
     let mut bomber = None;
-    for h in 0..template.len() {
-        let row: &str = template[h];
+    let mut spawns = vec![];
+    let mut ghosts = vec![];
+    for (h, row) in template.iter().map(AsRef::as_ref).enumerate() {
         width = cmp::max(width, row.len());
         for (w, c) in row.chars().enumerate() {
             match templates::cell_from_char(c) {
@@ -284,21 +1453,145 @@ pub fn new(template: &[&str]) -> Game {
                     bomber = Some((h, w));
                     m.insert((h, w), Cell::Empty);
                 }
+                templates::LandscapeFromChar::Spawn => {
+                    spawns.push((h, w));
+                    m.insert((h, w), Cell::Empty);
+                }
+                templates::LandscapeFromChar::Ghost { behaviour } => {
+                    ghosts.push(Ghost {
+                        coords: (h, w),
+                        behaviour,
+                        heading: (0, 0),
+                    });
+                    m.insert((h, w), Cell::Ghost);
+                }
                 templates::LandscapeFromChar::Unknown => panic!("Unknown char in template {}", c),
             }
         }
     }
-    Game {
-        width: width,
+    // The seed is pinned so the Created event is enough to rebuild the game.
+    let settings = Settings {
+        seed: Some(
+            settings
+                .seed
+                .unwrap_or_else(|| u64::from(rand::random::<u32>())),
+        ),
+        ..settings.clone()
+    };
+    let now = settings.clock.now();
+    let (clock, ghost_step) = match settings.mode {
+        Mode::RealTime => (settings.clock, 500),
+        Mode::Turns => (Clock::Manual(now), TURN_MILLIS),
+    };
+    let mut game = Game {
+        width,
         height: template.len(),
         landscape: m,
-        started: Utc::now(),
-        updated: Utc::now(),
-        bomberman: bomber.unwrap(),
+        started: now,
+        updated: now,
+        bombermen: vec![Bomberman::new(bomber.unwrap(), now)],
+        spawns,
         active: true,
         surrounding_size: 7,
-        bomb: None,
+        bombs: vec![],
+        blast_radius: settings.blast_radius,
+        bomb_capacity: settings.bombs,
+        ghosts,
+        ghost_step,
+        ghosts_moved: now,
+        rng: ChaCha8Rng::seed_from_u64(settings.seed.unwrap_or_default()),
+        clock,
+        mode: settings.mode,
+        turn: 0,
+        log: vec![],
+        hidden: settings.hidden,
+    };
+    game.record(Event::Created {
+        template: template.iter().map(|r| r.as_ref().to_string()).collect(),
+        settings: Settings {
+            clock: Clock::default(),
+            ..settings
+        },
+    });
+    game
+}
+
+// Rebuilds a game from its log. Every entry runs at its recorded instant on a
+// manual clock; real-time games then carry on on the given one. Instants must
+// run forwards and, in real-time games, not past that clock; in turn-based
+// games they must match the time the turns add up to.
+pub fn replay(log: &[LogEntry], clock: Clock) -> Result<Game, String> {
+    let (first, rest) = log.split_first().ok_or("The log is empty")?;
+    let now = clock.now();
+    if first.at > now {
+        return Err(format!("The log starts after {}", now));
     }
+    let mut game = match &first.event {
+        Event::Created { template, settings } => {
+            templates::validate(template)?;
+            new(
+                template,
+                &Settings {
+                    clock: Clock::Manual(first.at),
+                    ..settings.clone()
+                },
+            )
+        }
+        _ => return Err("The log must start with a Created event".to_string()),
+    };
+    for entry in rest {
+        if entry.at < game.clock.now() {
+            return Err(format!("Entry at {} is out of order", entry.at));
+        }
+        // Turns move the clock themselves, and may run ahead of it.
+        if game.mode == Mode::RealTime {
+            if entry.at > now {
+                return Err(format!("Entry at {} is after {}", entry.at, now));
+            }
+            game.clock = Clock::Manual(entry.at);
+        }
+        let player = match entry.event {
+            Event::Moved { player, .. }
+            | Event::MoveRejected { player, .. }
+            | Event::BombPlanted { player, .. } => Some(player),
+            _ => None,
+        };
+        if player.is_some_and(|p| p >= game.bombermen.len()) {
+            return Err(format!("Unknown player in {:?}", entry.event));
+        }
+        match &entry.event {
+            Event::Created { .. } => return Err("Duplicate Created event".to_string()),
+            Event::Joined { .. } => {
+                let _ = game.join();
+            }
+            Event::Turn { .. } => game.next_turn(),
+            Event::Moved {
+                player, direction, ..
+            }
+            | Event::MoveRejected {
+                player, direction, ..
+            } => {
+                let _ = game.bomberman_move(*player, *direction);
+            }
+            Event::BombPlanted { player, .. } => {
+                let _ = game.plant_bomb(*player);
+            }
+            Event::GhostsMoved { .. }
+            | Event::Blast { .. }
+            | Event::Finished { .. }
+            | Event::PowerUpCollected { .. } => game.tick(),
+        }
+        if game.mode == Mode::Turns && game.clock.now() != entry.at {
+            return Err(format!(
+                "Entry at {} is not on turn {}",
+                entry.at, game.turn
+            ));
+        }
+    }
+    if game.mode == Mode::RealTime {
+        game.clock = clock;
+    }
+    Ok(game)
 }
 
 #[cfg(test)]
@@ -307,24 +1600,690 @@ mod tests {
 
     #[test]
     fn test_small_1() {
-        let sut = new(templates::SMALL_1);
+        let sut = new(templates::SMALL_1, &Settings::default());
         assert_eq!(sut.height, 15);
         assert_eq!(sut.width, 15);
         assert_eq!(sut.landscape.len(), 15 * 15);
-        assert_eq!(sut.bomberman, (1, 1));
-        assert_eq!(sut.landscape.get(&(3, 13)).unwrap(), &Cell::Ghost);
+        assert_eq!(sut.bombermen[0].coords, (1, 1));
+        assert_eq!(sut.landscape.get(&(9, 7)).unwrap(), &Cell::Ghost);
         assert_eq!(sut.landscape.get(&(0, 13)).unwrap(), &Cell::Wall);
         assert_eq!(sut.landscape.get(&(1, 13)).unwrap(), &Cell::Brick);
         assert_eq!(sut.landscape.get(&(13, 13)).unwrap(), &Cell::OpenGate);
     }
 
+    #[test]
+    fn test_small_2() {
+        let sut = new(templates::SMALL_2, &Settings::default());
+        assert_eq!(sut.height, 15);
+        assert_eq!(sut.width, 15);
+        assert_eq!(sut.bombermen[0].coords, (1, 1));
+        assert_eq!(sut.landscape.get(&(13, 13)).unwrap(), &Cell::HiddenGate);
+        assert_eq!(sut.ghosts.len(), 2);
+    }
+
     #[test]
     fn test_wide_1() {
-        let sut = new(templates::WIDE_1);
+        let sut = new(templates::WIDE_1, &Settings::default());
         assert_eq!(sut.height, 15);
         assert_eq!(sut.width, 60);
         assert_eq!(sut.landscape.len(), 15 * 60);
-        assert_eq!(sut.bomberman, (1, 1));
+        assert_eq!(sut.bombermen[0].coords, (1, 1));
         assert_eq!(sut.landscape.get(&(1, 58)).unwrap(), &Cell::OpenGate);
     }
+
+    fn manual(template: &[&str]) -> Game {
+        manual_with(template, Settings::default())
+    }
+
+    fn manual_with(template: &[&str], settings: Settings) -> Game {
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let settings = Settings {
+            clock: Clock::Manual(start),
+            ..settings
+        };
+        new(template, &settings)
+    }
+
+    fn advance_ghosts(game: &mut Game, steps: i64) {
+        game.advance(Duration::milliseconds(steps * game.ghost_step));
+        game.haunt();
+    }
+
+    #[test]
+    fn test_corridor_ghost_stays_in_corridor() {
+        let mut sut = manual(&["XXXXXXX", "XM X  X", "XXXC XX", "XXXXXXX"]);
+        for _ in 0..10 {
+            advance_ghosts(&mut sut, 1);
+            assert!([(2, 3), (2, 4), (1, 4), (1, 5)].contains(&sut.ghosts[0].coords));
+        }
+    }
+
+    #[test]
+    fn test_chasing_ghost_catches_bomberman() {
+        let mut sut = manual(&["XXXXXXX", "XM   SX", "XXXXXXX"]);
+        advance_ghosts(&mut sut, 2);
+        assert_eq!(sut.ghosts[0].coords, (1, 3));
+        assert!(sut.active);
+        advance_ghosts(&mut sut, 2);
+        assert_eq!(sut.ghosts[0].coords, (1, 1));
+        assert!(!sut.active);
+        assert_eq!(sut.bombermen[0].outcome, Some(Outcome::KilledByGhost));
+    }
+
+    fn detonate(game: &mut Game) {
+        game.advance(Duration::seconds(4));
+        game.blast();
+    }
+
+    #[test]
+    fn test_blast_rays_stop_at_first_brick_and_walls() {
+        let settings = Settings {
+            blast_radius: 3,
+            ..Default::default()
+        };
+        let mut sut = manual_with(&["XXXXXXXX", "X BB M X", "XXXXX XX", "XXXXXBXX"], settings);
+        sut.plant_bomb(0).unwrap();
+        let area = sut.blast_area((1, 5), 3);
+        assert!(area.contains(&(1, 3)));
+        assert!(!area.contains(&(1, 2)));
+        assert!(area.contains(&(1, 7)));
+        assert!(area.contains(&(3, 5)));
+        detonate(&mut sut);
+        assert_eq!(sut.landscape.get(&(1, 3)).unwrap(), &Cell::Empty);
+        assert_eq!(sut.landscape.get(&(1, 2)).unwrap(), &Cell::Brick);
+        assert_eq!(sut.landscape.get(&(3, 5)).unwrap(), &Cell::Empty);
+        assert_eq!(sut.landscape.get(&(1, 7)).unwrap(), &Cell::Wall);
+    }
+
+    #[test]
+    fn test_blast_kills_ghosts_along_the_ray() {
+        let settings = Settings {
+            blast_radius: 3,
+            ..Default::default()
+        };
+        let mut sut = manual_with(&["XXXXXXX", "XMGG BX", "XXXXXXX"], settings);
+        sut.plant_bomb(0).unwrap();
+        detonate(&mut sut);
+        assert!(sut.ghosts.is_empty());
+        assert_eq!(sut.landscape.get(&(1, 2)).unwrap(), &Cell::Empty);
+        assert_eq!(sut.landscape.get(&(1, 3)).unwrap(), &Cell::Empty);
+        assert_eq!(sut.landscape.get(&(1, 5)).unwrap(), &Cell::Brick);
+    }
+
+    #[test]
+    fn test_blast_kills_bomberman_in_range() {
+        let mut sut = manual(&["XXXXX", "XM  X", "XXXXX"]);
+        sut.plant_bomb(0).unwrap();
+        sut.advance(Duration::seconds(1));
+        sut.bomberman_move(0, Direction::Right).unwrap();
+        assert_eq!(sut.bombermen[0].coords, (1, 2));
+        detonate(&mut sut);
+        assert!(!sut.active);
+        assert_eq!(sut.bombermen[0].outcome, Some(Outcome::KilledByBomb));
+        assert_eq!(sut.game_status(0).status, Status::Lost);
+    }
+
+    #[test]
+    fn test_blast_spares_bomberman_out_of_range() {
+        let mut sut = manual(&["XXXXX", "XM  X", "XXXXX"]);
+        sut.plant_bomb(0).unwrap();
+        sut.bombermen[0].coords = (1, 3);
+        detonate(&mut sut);
+        assert!(sut.active);
+        assert_eq!(sut.bombermen[0].outcome, None);
+        assert_eq!(sut.game_status(0).status, Status::Running);
+    }
+
+    #[test]
+    fn test_reaching_gate_wins() {
+        let mut sut = manual(&["XXXX", "XMOX", "XXXX"]);
+        sut.advance(Duration::seconds(1));
+        sut.bomberman_move(0, Direction::Right).unwrap();
+        let status = sut.game_status(0);
+        assert_eq!(status.status, Status::Won);
+        assert_eq!(status.outcome, Some(Outcome::ReachedGate));
+        assert!(status.elapsed >= 0);
+    }
+
+    #[test]
+    fn test_blast_reveals_hidden_gate() {
+        let mut sut = manual(&["XXXXX", "XM HX", "XXXXX"]);
+        assert!(sut.surrounding(0).gates.is_empty());
+        assert_eq!(sut.surrounding(0).bricks, vec![(1, 3)]);
+        sut.bombermen[0].coords = (1, 2);
+        sut.plant_bomb(0).unwrap();
+        sut.bombermen[0].coords = (1, 1);
+        detonate(&mut sut);
+        assert_eq!(sut.landscape.get(&(1, 3)).unwrap(), &Cell::OpenGate);
+        assert_eq!(sut.surrounding(0).gates, vec![(1, 3)]);
+        assert!(sut.surrounding(0).bricks.is_empty());
+    }
+
+    #[test]
+    fn test_blast_reveals_power_up_and_moving_collects_it() {
+        let mut sut = manual(&["XXXXXXX", "XM  bBX", "XXXXXXX"]);
+        assert_eq!(sut.surrounding(0).bricks, vec![(1, 4), (1, 5)]);
+        assert!(sut.surrounding(0).power_ups.is_empty());
+        assert_eq!(sut.board(false)[1], "XM  BBX");
+        assert_eq!(sut.board(true)[1], "XM  bBX");
+        sut.bombermen[0].coords = (1, 3);
+        sut.plant_bomb(0).unwrap();
+        sut.bombermen[0].coords = (1, 1);
+        detonate(&mut sut);
+        assert_eq!(
+            sut.landscape.get(&(1, 4)).unwrap(),
+            &Cell::PowerUp(PowerUp::ExtraBomb)
+        );
+        assert_eq!(sut.landscape.get(&(1, 5)).unwrap(), &Cell::Brick);
+        assert_eq!(
+            sut.surrounding(0).power_ups,
+            vec![((1, 4), PowerUp::ExtraBomb)]
+        );
+        assert_eq!(sut.board(false)[1], "XM  +BX");
+        sut.bombermen[0].coords = (1, 3);
+        sut.bomberman_move(0, Direction::Right).unwrap();
+        assert_eq!(sut.landscape.get(&(1, 4)).unwrap(), &Cell::Empty);
+        assert_eq!(sut.bombermen[0].extra_bombs, 1);
+        assert!(matches!(
+            sut.log().last().unwrap().event,
+            Event::PowerUpCollected {
+                player: 0,
+                power_up: PowerUp::ExtraBomb
+            }
+        ));
+        sut.bomb_capacity = usize::MAX;
+        sut.plant_bomb(0).unwrap();
+    }
+
+    #[test]
+    fn test_power_ups_change_the_bomberman() {
+        let mut sut = manual(&["XXXXXXXX", "XM     X", "XXXXXXXX"]);
+        sut.plant_bomb(0).unwrap();
+        assert_eq!(sut.plant_bomb(0), Err(Rejection::NoBombsLeft));
+        sut.bombermen[0].collect(PowerUp::ExtraBomb);
+        sut.bombermen[0].collect(PowerUp::Flame);
+        sut.bombermen[0].coords = (1, 2);
+        sut.plant_bomb(0).unwrap();
+        assert_eq!(sut.bombs[0].radius, 1);
+        assert_eq!(sut.bombs[1].radius, 2);
+        sut.blast_radius = usize::MAX;
+        sut.bomb_capacity = 2;
+        sut.bombermen[0].coords = (1, 3);
+        sut.plant_bomb(0).unwrap();
+        assert_eq!(sut.bombs[2].radius, usize::MAX);
+        assert_eq!(sut.blast_area((1, 3), usize::MAX).len(), 10);
+        assert_eq!(sut.bombermen[0].throttle(), 200);
+        sut.bombermen[0].collect(PowerUp::Speed);
+        assert_eq!(sut.bombermen[0].throttle(), 150);
+        for _ in 0..5 {
+            sut.bombermen[0].collect(PowerUp::Speed);
+        }
+        assert_eq!(sut.bombermen[0].throttle(), 50);
+    }
+
+    #[test]
+    fn test_arena_1_hosts_four_players() {
+        let mut sut = new(templates::ARENA_1, &Settings::default());
+        let second = sut.join().unwrap();
+        assert_eq!(second.index, 1);
+        assert_eq!(sut.player_by_token(second.token), Some(1));
+        assert_eq!(sut.bombermen[1].coords, (1, 19));
+        assert!(sut.join().is_ok());
+        assert!(sut.join().is_ok());
+        assert_eq!(sut.join().err(), Some(JoinRejection::Full));
+        assert_eq!(sut.player_by_token(Uuid::new_v4()), None);
+        let mut sut = new(templates::SMALL_1, &Settings::default());
+        assert_eq!(sut.join().err(), Some(JoinRejection::NoSpawnPoints));
+    }
+
+    #[test]
+    fn test_surroundings_list_rivals() {
+        let mut sut = manual(&["XXXXXX", "XM  PX", "XXXXXX"]);
+        sut.join().unwrap();
+        assert_eq!(sut.surrounding(0).bombermans, vec![(1, 1), (1, 4)]);
+        assert_eq!(sut.surrounding(1).bombermans, vec![(1, 4), (1, 1)]);
+    }
+
+    #[test]
+    fn test_surroundings_at_the_size_limit() {
+        let side = templates::MAX_SIZE;
+        let mut rows = vec![format!("X{}X", " ".repeat(side - 2)); side];
+        rows[0] = "X".repeat(side);
+        rows[side - 2] = format!("X{}OMX", " ".repeat(side - 4));
+        rows[side - 1] = "X".repeat(side);
+        assert_eq!(templates::validate(&rows), Ok(()));
+        let mut sut = new(&rows, &Settings::default());
+        let surroundings = sut.surrounding(0);
+        assert_eq!(surroundings.bombermans, vec![(side - 2, side - 2)]);
+        assert_eq!(surroundings.gates, vec![(side - 2, side - 3)]);
+        assert!(surroundings.wall.contains(&(side - 1, side - 1)));
+    }
+
+    #[test]
+    fn test_rival_reaching_gate_ends_the_game() {
+        let mut sut = manual(&["XXXXXX", "XM OPX", "XXXXXX"]);
+        sut.join().unwrap();
+        sut.advance(Duration::seconds(1));
+        sut.bomberman_move(1, Direction::Left).unwrap();
+        assert!(!sut.active);
+        assert_eq!(sut.game_status(1).status, Status::Won);
+        assert_eq!(sut.game_status(0).status, Status::Lost);
+        assert_eq!(sut.outcome(0), Some(Outcome::RivalReachedGate));
+    }
+
+    #[test]
+    fn test_bomb_kills_rival_and_game_goes_on() {
+        let mut sut = manual(&["XXXXXX", "XMP  X", "XXXXXX"]);
+        sut.join().unwrap();
+        sut.plant_bomb(0).unwrap();
+        sut.bombermen[0].coords = (1, 4);
+        detonate(&mut sut);
+        assert_eq!(sut.outcome(1), Some(Outcome::KilledByBomb));
+        assert_eq!(sut.outcome(0), None);
+        assert!(sut.active);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(templates::validate(templates::SMALL_1), Ok(()));
+        assert_eq!(templates::validate(templates::ARENA_1), Ok(()));
+        assert_eq!(
+            templates::validate(&["XXXX", "XMBX", "XBOX", "XXXX"]),
+            Ok(())
+        );
+        assert!(templates::validate(&["XXXX", "XMOX", "XXX"]).is_err());
+        assert!(templates::validate(&["XXXX", "XM?X", "XXOX", "XXXX"]).is_err());
+        assert!(templates::validate(&["XXXX", "XMO ", "XXXX"]).is_err());
+        assert!(templates::validate(&["XXXXX", "XMMOX", "XXXXX"]).is_err());
+        assert!(templates::validate(&["XXXXX", "XMXOX", "XXXXX"]).is_err());
+        assert!(templates::validate(&["XXXX", "XM X", "XXXX"]).is_err());
+        let mut wide = vec!["X".repeat(129); 3];
+        wide[1] = format!("XMO{}X", " ".repeat(125));
+        assert!(templates::validate(&wide).is_err());
+        let mut tall = vec![String::from("XXX"); 129];
+        tall[1] = String::from("XMX");
+        tall[2] = String::from("XOX");
+        assert!(templates::validate(&tall).is_err());
+    }
+
+    #[test]
+    fn test_generate_is_reproducible() {
+        let generation = templates::Generation {
+            width: 21,
+            height: 13,
+            ghosts: 3,
+            ..Default::default()
+        };
+        let rows = templates::generate(&generation, 42).unwrap();
+        assert_eq!(rows, templates::generate(&generation, 42).unwrap());
+        assert_ne!(rows, templates::generate(&generation, 43).unwrap());
+        assert_eq!(rows.len(), 13);
+        assert_eq!(rows[0].len(), 21);
+        assert_eq!(&rows[1][..4], "XM  ");
+        let ghosts: usize = rows
+            .iter()
+            .map(|r| r.chars().filter(|c| "GCS".contains(*c)).count())
+            .sum();
+        assert_eq!(ghosts, 3);
+        let gates: usize = rows
+            .iter()
+            .map(|r| r.chars().filter(|c| "OH".contains(*c)).count())
+            .sum();
+        assert_eq!(gates, 1);
+        let with_power_ups = templates::Generation {
+            power_ups: 1.0,
+            ..generation
+        };
+        let hidden = templates::generate(&with_power_ups, 42).unwrap();
+        assert_eq!(hidden.len(), rows.len());
+        assert!(hidden.iter().all(|r| !r.contains('B')));
+        assert!(hidden.iter().any(|r| r.contains(['b', 'f', 's'])));
+        let spawns: Vec<(usize, usize)> = (0..rows.len())
+            .flat_map(|h| (0..21).map(move |w| (h, w)))
+            .filter(|(h, w)| rows[*h].as_bytes()[*w] == b'P')
+            .collect();
+        assert_eq!(spawns, vec![(1, 19), (11, 1), (11, 19)]);
+        let mut game = new(&rows, &Settings::default());
+        for _ in 0..3 {
+            game.join().unwrap();
+        }
+        assert_eq!(game.join().err(), Some(JoinRejection::Full));
+    }
+
+    #[test]
+    fn test_generate_rejects_bad_sizes() {
+        let generation = templates::Generation {
+            width: 3,
+            ..Default::default()
+        };
+        assert!(templates::generate(&generation, 1).is_err());
+        let generation = templates::Generation {
+            bricks: 1.5,
+            ..Default::default()
+        };
+        assert!(templates::generate(&generation, 1).is_err());
+    }
+
+    #[test]
+    fn test_moves_are_throttled() {
+        let mut sut = manual(&["XXXXXX", "XM   X", "XXXXXX"]);
+        assert_eq!(
+            sut.bomberman_move(0, Direction::Right),
+            Err(Rejection::Throttled)
+        );
+        assert_eq!(sut.bombermen[0].coords, (1, 1));
+        sut.advance(Duration::milliseconds(201));
+        sut.bomberman_move(0, Direction::Right).unwrap();
+        assert_eq!(sut.bombermen[0].coords, (1, 2));
+        sut.advance(Duration::milliseconds(200));
+        assert_eq!(
+            sut.bomberman_move(0, Direction::Right),
+            Err(Rejection::Throttled)
+        );
+        assert_eq!(sut.bombermen[0].coords, (1, 2));
+        sut.advance(Duration::milliseconds(1));
+        sut.bomberman_move(0, Direction::Right).unwrap();
+        assert_eq!(sut.bombermen[0].coords, (1, 3));
+    }
+
+    #[test]
+    fn test_bomb_fuse() {
+        let mut sut = manual(&["XXXXXX", "XM BBX", "XXXXXX"]);
+        sut.plant_bomb(0).unwrap();
+        sut.advance(Duration::milliseconds(3999));
+        assert_eq!(sut.bomb_status(0)[0].fuse, 1);
+        sut.advance(Duration::milliseconds(1));
+        assert!(sut.bomb_status(0).is_empty());
+        assert_eq!(sut.game_status(0).elapsed, 4000);
+        assert_eq!(sut.game_status(0).outcome, Some(Outcome::KilledByBomb));
+    }
+
+    #[test]
+    fn test_bombs_tick_on_their_own_fuses() {
+        let settings = Settings {
+            bombs: 2,
+            ..Default::default()
+        };
+        let mut sut = manual_with(&["XXXXXXXXX", "XM      X", "XXXXXXXXX"], settings);
+        sut.plant_bomb(0).unwrap();
+        assert_eq!(sut.plant_bomb(0), Err(Rejection::BombInPlace));
+        sut.advance(Duration::milliseconds(1000));
+        sut.bombermen[0].coords = (1, 4);
+        sut.plant_bomb(0).unwrap();
+        assert_eq!(sut.plant_bomb(0), Err(Rejection::NoBombsLeft));
+        sut.bombermen[0].coords = (1, 7);
+        let status = sut.bomb_status(0);
+        assert_eq!(status.len(), 2);
+        assert_eq!((status[0].coords, status[0].fuse), ((1, 1), 3000));
+        assert_eq!((status[1].coords, status[1].fuse), ((1, 4), 4000));
+        assert_eq!((status[0].planted, status[1].planted), (0, 1000));
+        assert_eq!(status[1].turns, None);
+        assert_eq!(status[1].area, vec![(1, 4), (0, 4), (2, 4), (1, 3), (1, 5)]);
+        sut.advance(Duration::milliseconds(3000));
+        let status = sut.bomb_status(0);
+        assert_eq!(status.len(), 1);
+        assert_eq!((status[0].coords, status[0].fuse), ((1, 4), 1000));
+        sut.plant_bomb(0).unwrap();
+        assert_eq!(sut.bomb_status(0).len(), 2);
+        assert!(sut.active);
+    }
+
+    #[test]
+    fn test_blast_sets_off_bombs_in_reach() {
+        let settings = Settings {
+            bombs: 3,
+            ..Default::default()
+        };
+        let mut sut = manual_with(&["XXXXXXXXX", "XM  B   X", "XXXXXXXXX"], settings);
+        for column in 1..=3 {
+            sut.bombermen[0].coords = (1, column);
+            sut.plant_bomb(0).unwrap();
+            sut.advance(Duration::milliseconds(1000));
+        }
+        sut.bombermen[0].coords = (1, 7);
+        let reach = sut.bomb_surrounding(0).unwrap();
+        assert_eq!(reach.bricks, vec![(1, 4)]);
+        sut.advance(Duration::milliseconds(1000));
+        assert!(sut.bomb_status(0).is_empty());
+        assert_eq!(sut.landscape.get(&(1, 4)).unwrap(), &Cell::Empty);
+        assert!(sut.active);
+        let blasts: Vec<(Coord, Vec<Coord>)> = sut
+            .log()
+            .iter()
+            .filter_map(|e| match &e.event {
+                Event::Blast {
+                    coords, chained, ..
+                } => Some((*coords, chained.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            blasts,
+            vec![
+                ((1, 1), vec![(1, 2)]),
+                ((1, 2), vec![(1, 3)]),
+                ((1, 3), vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chained_blasts_burn_at_once() {
+        let settings = Settings {
+            bombs: 2,
+            blast_radius: 2,
+            ..Default::default()
+        };
+        let mut sut = manual_with(&["XXXXXXXXX", "XM BB   X", "XXXXXXXXX"], settings);
+        sut.plant_bomb(0).unwrap();
+        sut.advance(Duration::milliseconds(1000));
+        sut.bombermen[0].coords = (1, 2);
+        sut.plant_bomb(0).unwrap();
+        sut.bombermen[0].coords = (1, 7);
+        sut.advance(Duration::milliseconds(3000));
+        assert!(sut.bomb_status(0).is_empty());
+        // The second bomb's ray still stops at the brick the first one burns.
+        assert_eq!(sut.landscape.get(&(1, 3)).unwrap(), &Cell::Empty);
+        assert_eq!(sut.landscape.get(&(1, 4)).unwrap(), &Cell::Brick);
+        assert!(sut.active);
+    }
+
+    #[test]
+    fn test_bombs_block_the_way() {
+        let mut sut = manual(&["XXXXXX", "XM   X", "XXXXXX"]);
+        sut.plant_bomb(0).unwrap();
+        assert_eq!(sut.surrounding(0).bombs, vec![(1, 1)]);
+        assert!(!sut.ghost_can_enter((1, 1)));
+        sut.advance(Duration::milliseconds(201));
+        sut.bomberman_move(0, Direction::Right).unwrap();
+        sut.advance(Duration::milliseconds(201));
+        assert_eq!(
+            sut.bomberman_move(0, Direction::Left),
+            Err(Rejection::BlockedByBomb)
+        );
+        assert_eq!(sut.bombermen[0].coords, (1, 2));
+        assert!(matches!(
+            sut.log().last().unwrap().event,
+            Event::MoveRejected {
+                reason: Rejection::BlockedByBomb,
+                ..
+            }
+        ));
+        sut.advance(Duration::milliseconds(4000));
+        assert!(sut.surrounding(0).bombs.is_empty());
+        assert!(sut.ghost_can_enter((1, 1)));
+    }
+
+    #[test]
+    fn test_turns() {
+        let settings = Settings {
+            mode: Mode::Turns,
+            ..Default::default()
+        };
+        let mut sut = new(
+            // A chasing ghost would find its way barred by the bomb.
+            &["XXXXXXXXXX", "X M     CX", "X XXXXHXXX", "XXXXXXXXXX"],
+            &settings,
+        );
+        sut.next_turn();
+        sut.plant_bomb(0).unwrap();
+        assert_eq!(sut.plant_bomb(0), Err(Rejection::NoBombsLeft));
+        sut.bomberman_move(0, Direction::Left).unwrap();
+        assert_eq!(
+            sut.bomberman_move(0, Direction::Down),
+            Err(Rejection::Throttled)
+        );
+        assert_eq!(sut.bombermen[0].coords, (1, 1));
+        assert_eq!(sut.ghosts[0].coords, (1, 7));
+        sut.next_turn();
+        sut.bomberman_move(0, Direction::Down).unwrap();
+        assert_eq!(sut.bombermen[0].coords, (2, 1));
+        sut.next_turn();
+        sut.next_turn();
+        let status = sut.bomb_status(0);
+        assert_eq!(status[0].fuse, TURN_MILLIS);
+        assert_eq!(status[0].turns, Some(1));
+        assert_eq!(status[0].planted, TURN_MILLIS);
+        assert_eq!(sut.ghosts[0].coords, (1, 4));
+        sut.next_turn();
+        assert!(sut.bomb_status(0).is_empty());
+        assert!(sut.ghosts.is_empty());
+        let status = sut.game_status(0);
+        assert_eq!(status.turn, Some(5));
+        assert_eq!(status.status, Status::Running);
+        let replayed = replay(sut.log(), Clock::Wall).unwrap();
+        assert_eq!(replayed.log(), sut.log());
+        assert_eq!(replayed.turn, 5);
+        let mut forged = sut.log().to_vec();
+        forged.last_mut().unwrap().at = Utc.ymd(262143, 12, 31).and_hms_milli(23, 59, 59, 500);
+        assert!(replay(&forged, Clock::Wall).is_err());
+        sut.clock = Clock::Manual(forged.last().unwrap().at);
+        sut.next_turn();
+        assert_eq!(sut.turn, 5);
+    }
+
+    #[test]
+    fn test_execute_skips_commands_outside_capabilities() {
+        let mut sut = manual(&["XXXXX", "XM  X", "XXXXX"]);
+        let commands = [Commands::PlantBomb, Commands::FetchBombStatus];
+        let v1 = Capabilities {
+            bombs: false,
+            bomb_surrounding: false,
+            game_status: false,
+        };
+        let replies = sut.execute(0, &commands, &v1);
+        assert!(matches!(replies[..], [Reply::Skipped, Reply::Skipped]));
+        assert!(sut.bombs.is_empty());
+        let replies = sut.execute(0, &commands, &Capabilities::ALL);
+        assert!(matches!(
+            replies[..],
+            [Reply::Done(Ok(())), Reply::BombStatus(ref b)] if b.len() == 1
+        ));
+    }
+
+    #[test]
+    fn test_outcomes_are_snake_case() {
+        assert_eq!(
+            serde_json::to_string(&Outcome::KilledByBomb).unwrap(),
+            "\"killed_by_bomb\""
+        );
+        assert_eq!(serde_json::to_string(&Status::Won).unwrap(), "\"won\"");
+    }
+
+    #[test]
+    fn test_board() {
+        let mut sut = manual(&["XXXXXXX", "XM  BHX", "X XXX X", "XP SO X", "XXXXXXX"]);
+        sut.join().unwrap();
+        assert_eq!(
+            sut.board(true),
+            vec!["XXXXXXX", "XM  BHX", "X XXX X", "XP SO X", "XXXXXXX"]
+        );
+        sut.advance(Duration::milliseconds(300));
+        sut.plant_bomb(0).unwrap();
+        assert_eq!(sut.board(false)[1], "X@  BBX");
+        sut.bomberman_move(0, Direction::Right).unwrap();
+        assert_eq!(sut.board(false)[1], "X*M BBX");
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut sut = manual(&["XXXXXXX", "XM B  X", "X XXX X", "XPHXG X", "XXXXXXX"]);
+        sut.join().unwrap();
+        sut.advance(Duration::milliseconds(300));
+        sut.bomberman_move(0, Direction::Right).unwrap();
+        assert_eq!(
+            sut.bomberman_move(0, Direction::Right),
+            Err(Rejection::Throttled)
+        );
+        sut.plant_bomb(0).unwrap();
+        sut.advance(Duration::milliseconds(300));
+        sut.bomberman_move(0, Direction::Left).unwrap();
+        sut.bomberman_move(1, Direction::Up).unwrap();
+        sut.advance(Duration::seconds(4));
+        assert_eq!(
+            sut.bomberman_move(1, Direction::Right),
+            Err(Rejection::Blocked)
+        );
+        assert_eq!(
+            sut.bomberman_move(0, Direction::Right),
+            Err(Rejection::GameOver)
+        );
+        assert_eq!(sut.outcome(0), Some(Outcome::KilledByBomb));
+        let log: Vec<LogEntry> = sut
+            .log()
+            .iter()
+            .map(|e| serde_json::from_str(&serde_json::to_string(e).unwrap()).unwrap())
+            .collect();
+        assert!(matches!(
+            log[3].event,
+            Event::MoveRejected {
+                player: 0,
+                reason: Rejection::Throttled,
+                ..
+            }
+        ));
+        assert!(matches!(
+            log.last().unwrap().event,
+            Event::MoveRejected {
+                player: 0,
+                reason: Rejection::GameOver,
+                ..
+            }
+        ));
+        assert!(log.iter().any(|e| e.event
+            == Event::Blast {
+                coords: (1, 2),
+                destroyed: vec![(1, 3)],
+                area: vec![(1, 2), (0, 2), (2, 2), (1, 1), (1, 3)],
+                chained: vec![],
+            }));
+        let mut replayed = replay(&log, sut.clock).unwrap();
+        assert_eq!(
+            serde_json::to_string(replayed.log()).unwrap(),
+            serde_json::to_string(sut.log()).unwrap()
+        );
+        assert_eq!(replayed.landscape, sut.landscape);
+        assert_eq!(replayed.ghosts[0].coords, sut.ghosts[0].coords);
+        assert_eq!(replayed.outcome(0), Some(Outcome::KilledByBomb));
+        assert_eq!(replayed.bombermen[1].coords, (2, 1));
+        assert!(replay(&log[1..], sut.clock).is_err());
+        let resumed = replay(&log, Clock::Wall).unwrap();
+        assert_eq!(resumed.clock, Clock::Wall);
+        let mut shuffled = log.clone();
+        shuffled.swap(1, 2);
+        assert!(replay(&shuffled, Clock::Wall).is_err());
+        let start = log[0].at;
+        assert!(replay(&log, Clock::Manual(start)).is_err());
+        for template in [
+            vec!["XXXX", "XM?X", "XXOX", "XXXX"],
+            vec!["XXXX", "X OX", "XXXX"],
+        ] {
+            let mut forged = log.clone();
+            forged[0].event = Event::Created {
+                template: template.iter().map(|r| r.to_string()).collect(),
+                settings: Settings::default(),
+            };
+            assert!(replay(&forged, Clock::Wall).is_err());
+        }
+    }
 }
@@ -1,184 +1,766 @@
 use anyhow::anyhow;
-use serde::{Deserialize, Serialize};
+use async_std::prelude::*;
+use async_std::task;
+use serde::Deserialize;
 use std::env;
-use tide::{Error, Request, StatusCode};
+use std::time::Duration;
+use tide::{Endpoint, Error, Request, StatusCode};
+use tide_websockets::{Message, WebSocket, WebSocketConnection};
 use uuid::Uuid;
 
 mod game;
 mod responses;
 mod state;
+mod storage;
+
+use game::Commands;
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
     let listen_on: String =
         env::var("LISTEN_ON").unwrap_or_else(|_| String::from("127.0.0.1:8080"));
     println!("Listening on: {}", listen_on);
-    let state = state::new(1024);
+    let state = match env::var("STORAGE_DIR") {
+        Ok(dir) => {
+            println!("Storing games in: {}", dir);
+            state::restore(1024, storage::new(&dir)?)?
+        }
+        Err(_) => state::new(1024),
+    };
+    let state = state.with_admin_key(env::var("ADMIN_KEY").ok());
+    app(state).listen(listen_on).await?;
+    Ok(())
+}
+
+fn app(state: state::State) -> tide::Server<state::State> {
     let mut app = tide::with_state(state);
+    app.with(tide::utils::After(responses::error));
     app.at("/v1/game/new/random").post(random_new);
+    app.at("/v1/game/new/generated").post(generated_new);
     app.at("/v1/game/new/:name").post(new_game);
+    app.at("/v1/game/replay").post(replay_game);
     app.at("/v1/game/").get(list_games);
-    app.at("/v1/game/:uuid").post(command);
-    app.at("/v2/game/:uuid").post(command2);
-    app.at("/v3/game/:uuid").post(command3);
-    app.listen(listen_on).await?;
-    Ok(())
+    app.at("/v1/template").post(new_template);
+    app.at("/v1/game/:uuid").post(|req| command(req, &V1));
+    app.at("/v1/game/:uuid/join").post(join_game);
+    app.at("/v1/game/:uuid/log").get(game_log);
+    app.at("/v1/game/:uuid/board").get(board);
+    app.at("/v2/game/:uuid").post(|req| command(req, &V2));
+    app.at("/v3/game/:uuid").post(|req| command(req, &V3));
+    app.at("/v4/game/:uuid").post(|req| command(req, &V4));
+    app.at("/v4/game/:uuid/stream").get(stream_upgrade);
+    app.at("/watch").get(watch_index);
+    app.at("/watch/:uuid").get(watch);
+    app
 }
 
 fn not_found() -> Error {
     Error::new(StatusCode::NotFound, anyhow!("Not found"))
 }
 
-fn create_new_game(req: Request<state::State>, game: game::Game) -> tide::Result {
+fn unknown_game() -> Error {
+    Error::new(StatusCode::NotFound, anyhow!("Unknown game"))
+}
+
+// The game named by the :uuid parameter, which must still be held in state.
+fn game_uuid(req: &Request<state::State>) -> tide::Result<Uuid> {
+    let uuid = Uuid::parse_str(req.param("uuid")?).map_err(|_| unknown_game())?;
+    if !req.state().apply_to_game(uuid, false, |_| true) {
+        return Err(unknown_game());
+    }
+    Ok(uuid)
+}
+
+async fn body<T: serde::de::DeserializeOwned>(req: &mut Request<state::State>) -> tide::Result<T> {
+    req.body_json()
+        .await
+        .map_err(|e| Error::from_str(StatusCode::BadRequest, e.to_string()))
+}
+
+fn forbidden() -> Error {
+    Error::new(StatusCode::Forbidden, anyhow!("Unknown player token"))
+}
+
+// The player whose token came with the request.
+fn player(req: &Request<state::State>, uuid: Uuid) -> tide::Result<usize> {
+    let token = req.header("X-Player-Token").map(|t| t.as_str().to_string());
+    player_by_token(req, uuid, token)
+}
+
+// Browsers can't set headers on WebSocket requests, so streams also take the
+// token as a `token` query parameter. Nothing else does: URLs end up in logs.
+fn stream_player(req: &Request<state::State>, uuid: Uuid) -> tide::Result<usize> {
+    let token = req
+        .header("X-Player-Token")
+        .map(|t| t.as_str().to_string())
+        .or_else(|| {
+            req.url()
+                .query_pairs()
+                .find(|(k, _)| k == "token")
+                .map(|(_, v)| v.into_owned())
+        });
+    player_by_token(req, uuid, token)
+}
+
+fn player_by_token(
+    req: &Request<state::State>,
+    uuid: Uuid,
+    token: Option<String>,
+) -> tide::Result<usize> {
+    let token = token
+        .ok_or_else(|| Error::new(StatusCode::Unauthorized, anyhow!("Missing player token")))?;
+    let token = Uuid::parse_str(&token).map_err(|_| forbidden())?;
+    req.state()
+        .apply_to_game(uuid, None, |g| g.player_by_token(token))
+        .ok_or_else(forbidden)
+}
+
+fn create_new_game(
+    req: Request<state::State>,
+    game: game::Game,
+    seed: Option<u64>,
+) -> tide::Result {
     let (h, w) = (game.height, game.width);
+    let token = game.creator().token;
     let uuid = req.state().insert_and_evict(game);
     responses::new_game_created(&responses::NewGame {
-        uuid: uuid,
+        uuid,
+        token,
         width: w,
         height: h,
+        seed,
+    })
+}
+
+// Game settings from the query string, on the clock the state hands out.
+fn settings(req: &Request<state::State>) -> tide::Result<game::Settings> {
+    let settings: game::Settings = req.query()?;
+    let max = game::templates::MAX_SIZE;
+    if settings.bombs > max {
+        return Err(Error::new(
+            StatusCode::BadRequest,
+            anyhow!("Bombs must be at most {}", max),
+        ));
+    }
+    // No blast can reach further than across the largest map.
+    if settings.blast_radius > max {
+        return Err(Error::new(
+            StatusCode::BadRequest,
+            anyhow!("Blast radius must be at most {}", max),
+        ));
+    }
+    Ok(game::Settings {
+        clock: req.state().clock(),
+        ..settings
     })
 }
 
 async fn random_new(req: Request<state::State>) -> tide::Result {
-    let game = game::new(game::templates::random());
-    create_new_game(req, game)
+    let settings = settings(&req)?;
+    let game = game::new(game::templates::random(), &settings);
+    create_new_game(req, game, None)
+}
+
+async fn generated_new(req: Request<state::State>) -> tide::Result {
+    let mut settings = settings(&req)?;
+    let generation: game::templates::Generation = req.query()?;
+    let seed = generation
+        .seed
+        .unwrap_or_else(|| u64::from(rand::random::<u32>()));
+    let rows = game::templates::generate(&generation, seed)
+        .map_err(|e| Error::from_str(StatusCode::BadRequest, e))?;
+    settings.seed = Some(seed);
+    let game = game::new(&rows, &settings);
+    create_new_game(req, game, Some(seed))
 }
 
 async fn new_game(req: Request<state::State>) -> tide::Result {
     let name = req.param("name")?;
-    let game = game::templates::by_name(name)
-        .map(game::new)
-        .ok_or(not_found())?;
-    create_new_game(req, game)
+    let settings = settings(&req)?;
+    let game = match game::templates::by_name(name) {
+        Some(t) => game::new(t, &settings),
+        None => {
+            let rows = req.state().template(name).ok_or_else(not_found)?;
+            game::new(&rows, &settings)
+        }
+    };
+    create_new_game(req, game, None)
+}
+
+#[derive(Deserialize)]
+struct NewTemplate {
+    name: String,
+    rows: Vec<String>,
+}
+
+async fn new_template(mut req: Request<state::State>) -> tide::Result {
+    let template: NewTemplate = body(&mut req).await?;
+    game::templates::validate(&template.rows)
+        .map_err(|e| Error::from_str(StatusCode::BadRequest, e))?;
+    // These names are taken by the /v1/game/new/ routes.
+    if ["random", "generated"].contains(&template.name.to_lowercase().as_str()) {
+        return Err(Error::new(
+            StatusCode::BadRequest,
+            anyhow!("Template name {} is reserved", template.name),
+        ));
+    }
+    let exists = Error::new(
+        StatusCode::Conflict,
+        anyhow!("Template {} already exists", template.name),
+    );
+    if game::templates::by_name(&template.name).is_some() {
+        return Err(exists);
+    }
+    if !req
+        .state()
+        .insert_template(&template.name, template.rows.clone())
+    {
+        return Err(exists);
+    }
+    responses::template_created(&template.name, &template.rows)
+}
+
+async fn join_game(req: Request<state::State>) -> tide::Result {
+    let uuid = game_uuid(&req)?;
+    let conflict = |message: &str| Err(Error::from_str(StatusCode::Conflict, message.to_string()));
+    match req.state().apply_to_game(uuid, None, |g| Some(g.join())) {
+        None => Err(unknown_game()),
+        Some(Ok(player)) => responses::player_joined(&player),
+        Some(Err(game::JoinRejection::NoSpawnPoints)) => conflict("Map has no spawn points"),
+        Some(Err(game::JoinRejection::Full)) => conflict("Game is full"),
+        Some(Err(game::JoinRejection::GameOver)) => conflict("Game is over"),
+    }
+}
+
+async fn game_log(req: Request<state::State>) -> tide::Result {
+    let uuid = game_uuid(&req)?;
+    let admin = is_admin(&req);
+    let log = req
+        .state()
+        .apply_to_game(uuid, None, |g| Some(g.published_log(admin)))
+        .ok_or_else(not_found)?;
+    responses::game_log(&log)
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct BoardQuery {
+    hidden: bool,
+}
+
+async fn board(req: Request<state::State>) -> tide::Result {
+    let uuid = game_uuid(&req)?;
+    let query: BoardQuery = req.query()?;
+    if query.hidden && !is_admin(&req) {
+        return Err(Error::from_str(
+            StatusCode::Forbidden,
+            "Hidden cells are shown to admins only",
+        ));
+    }
+    let rows = req
+        .state()
+        .apply_to_game(uuid, None, |g| Some(g.board(query.hidden)))
+        .ok_or_else(not_found)?;
+    responses::board(&rows)
+}
+
+async fn watch_index(_req: Request<state::State>) -> tide::Result {
+    responses::html(include_str!("static/index.html"))
+}
+
+// The page polls the board endpoint, so it only needs to know the game exists.
+async fn watch(req: Request<state::State>) -> tide::Result {
+    game_uuid(&req)?;
+    responses::html(include_str!("static/watch.html"))
+}
+
+// Takes a log as served by game_log and starts a new game in its final state.
+// Logs served to players mask hidden cells, so maps with a hidden gate only
+// replay from the full log admins get.
+async fn replay_game(mut req: Request<state::State>) -> tide::Result {
+    let body = req.body_string().await?;
+    let log = body
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<game::LogEntry>, _>>()
+        .map_err(|e| Error::new(StatusCode::BadRequest, e))?;
+    let game = game::replay(&log, req.state().clock())
+        .map_err(|e| Error::from_str(StatusCode::BadRequest, e))?;
+    create_new_game(req, game, None)
+}
+
+fn is_admin(req: &Request<state::State>) -> bool {
+    match req.header("X-Admin-Key") {
+        Some(key) => req.state().is_admin_key(key.as_str()),
+        None => false,
+    }
 }
 
 async fn list_games(req: Request<state::State>) -> tide::Result {
-    let games = req.state().list_games();
+    let games = req.state().list_games(is_admin(&req));
     responses::list_games(&games)
 }
 
-#[derive(Deserialize, Serialize, Eq, PartialEq)]
-enum Direction {
-    Up,
-    Down,
-    Left,
-    Right,
+// A protocol version: which commands it acts on and how it encodes the replies.
+struct Version {
+    capabilities: game::Capabilities,
+    encode: responses::Encoder,
 }
 
-#[derive(Deserialize, Serialize, Eq, PartialEq)]
-#[serde(tag = "name")]
-enum Commands {
-    MoveBomberman { direction: Direction },
-    FetchSurrounding,
-    FetchBombSurrounding,
-    PlantBomb,
-    FetchBombStatus,
+const V1: Version = Version {
+    capabilities: game::Capabilities {
+        bombs: false,
+        bomb_surrounding: false,
+        game_status: false,
+    },
+    encode: responses::command,
+};
+
+const V2: Version = Version {
+    capabilities: game::Capabilities {
+        bombs: true,
+        bomb_surrounding: false,
+        game_status: false,
+    },
+    encode: responses::command2,
+};
+
+const V3: Version = Version {
+    capabilities: game::Capabilities::ALL,
+    encode: responses::command3,
+};
+
+const V4: Version = Version {
+    capabilities: game::Capabilities::ALL,
+    encode: responses::command4,
+};
+
+// The chain comes either nested through `additional` or as a plain array.
+fn chain(body: serde_json::Value) -> serde_json::Result<Vec<Commands>> {
+    if body.is_array() {
+        serde_json::from_value(body)
+    } else {
+        let command: game::Command = serde_json::from_value(body)?;
+        Ok(game::flatten(&command).into_iter().cloned().collect())
+    }
 }
 
-#[derive(Deserialize, Serialize)]
-struct Command {
-    command: Commands,
-    additional: Option<Box<Command>>,
+async fn command(mut req: Request<state::State>, version: &Version) -> tide::Result {
+    let uuid = game_uuid(&req)?;
+    let player = player(&req, uuid)?;
+    let commands = chain(body(&mut req).await?)
+        .map_err(|e| Error::from_str(StatusCode::BadRequest, e.to_string()))?;
+    let (replies, outcome) = req
+        .state()
+        .apply_to_game(uuid, None, |g| {
+            let replies = g.execute(player, &commands, &version.capabilities);
+            Some((replies, g.outcome(player)))
+        })
+        .ok_or_else(unknown_game)?;
+    responses::encoded((version.encode)(&commands, &replies, &outcome))
+}
+
+fn frame(state: &state::State, uuid: Uuid, player: usize) -> Option<serde_json::Value> {
+    state.apply_to_game(uuid, None, |g| {
+        Some(responses::frame(
+            &g.surrounding(player),
+            &g.bomb_status(player),
+            &g.bomb_surrounding(player),
+            &g.game_status(player),
+        ))
+    })
 }
 
-fn flatten<'a>(command: &'a Command) -> Vec<&'a Commands> {
-    let mut result = vec![];
-    let mut current: &Command = command;
+const STREAM_POLL: Duration = Duration::from_millis(50);
+
+// Pushes a frame whenever the game changed, checking every STREAM_POLL.
+async fn push_updates(state: state::State, uuid: Uuid, player: usize, conn: WebSocketConnection) {
+    let mut seen = None;
     loop {
-        result.push(&current.command);
-        match &current.additional {
-            None => {
-                break;
-            }
-            Some(v) => {
-                current = &v;
-                continue;
+        let updated = state.apply_to_game(uuid, None, |g| Some(g.last_update()));
+        if updated.is_none() {
+            return;
+        }
+        if updated != seen {
+            seen = updated;
+            match frame(&state, uuid, player) {
+                Some(f) if conn.send_json(&f).await.is_ok() => {}
+                _ => return,
             }
-        };
+        }
+        task::sleep(STREAM_POLL).await;
     }
-    result
-}
-
-async fn command(mut req: Request<state::State>) -> tide::Result {
-    let uuid = req.param("uuid")?;
-    let uuid = Uuid::parse_str(uuid)?;
-    let command: Command = req.body_json().await?;
-    let state = req.state();
-    let mut surrounding: Option<game::Surroundings> = None;
-    for command in flatten(&command) {
-        match command {
-            Commands::MoveBomberman { direction } => match direction {
-                Direction::Up => state.apply_to_game(uuid, (), |g| g.bomberman_up()),
-                Direction::Down => state.apply_to_game(uuid, (), |g| g.bomberman_down()),
-                Direction::Left => state.apply_to_game(uuid, (), |g| g.bomberman_left()),
-                Direction::Right => state.apply_to_game(uuid, (), |g| g.bomberman_right()),
-            },
-            Commands::FetchSurrounding => {
-                surrounding = state.apply_to_game(uuid, None, |g| Some(g.surrounding()))
-            }
-            Commands::PlantBomb => (),
-            Commands::FetchBombStatus => (),
-            Commands::FetchBombSurrounding => (),
-        }
-    }
-    responses::command(&surrounding)
-}
-
-async fn command2(mut req: Request<state::State>) -> tide::Result {
-    let uuid = req.param("uuid")?;
-    let uuid = Uuid::parse_str(uuid)?;
-    let command: Command = req.body_json().await?;
-    let state = req.state();
-    let mut surrounding: Option<game::Surroundings> = None;
-    let mut bomb_status: Option<game::BombStatus> = None;
-    for command in flatten(&command) {
-        match command {
-            Commands::MoveBomberman { direction } => match direction {
-                Direction::Up => state.apply_to_game(uuid, (), |g| g.bomberman_up()),
-                Direction::Down => state.apply_to_game(uuid, (), |g| g.bomberman_down()),
-                Direction::Left => state.apply_to_game(uuid, (), |g| g.bomberman_left()),
-                Direction::Right => state.apply_to_game(uuid, (), |g| g.bomberman_right()),
-            },
-            Commands::FetchSurrounding => {
-                surrounding = state.apply_to_game(uuid, None, |g| Some(g.surrounding()))
+}
+
+// Unknown games and bad tokens get a plain HTTP error instead of a socket that
+// closes without a word.
+async fn stream_upgrade(req: Request<state::State>) -> tide::Result {
+    let uuid = game_uuid(&req)?;
+    stream_player(&req, uuid)?;
+    WebSocket::new(stream).call(req).await
+}
+
+async fn stream(req: Request<state::State>, mut conn: WebSocketConnection) -> tide::Result<()> {
+    let uuid = game_uuid(&req)?;
+    let player = stream_player(&req, uuid)?;
+    let state = req.state().clone();
+    let pusher = task::spawn(push_updates(state.clone(), uuid, player, conn.clone()));
+    while let Some(Ok(message)) = conn.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        match serde_json::from_str(&text).and_then(chain) {
+            Err(e) => {
+                conn.send_json(&serde_json::json!({ "error": e.to_string() }))
+                    .await?
             }
-            Commands::PlantBomb => state.apply_to_game(uuid, (), |g| g.plant_bomb()),
-            Commands::FetchBombStatus => {
-                bomb_status = state.apply_to_game(uuid, None, |g| g.bomb_status())
+            Ok(commands) => {
+                // Fetches are answered by the frame sent below.
+                state.apply_to_game(uuid, (), |g| {
+                    g.execute(player, &commands, &game::Capabilities::ALL);
+                });
+                match frame(&state, uuid, player) {
+                    Some(f) => conn.send_json(&f).await?,
+                    None => break,
+                }
             }
-            Commands::FetchBombSurrounding => (),
-        }
-    }
-    responses::command2(&surrounding, &bomb_status)
-}
-
-async fn command3(mut req: Request<state::State>) -> tide::Result {
-    let uuid = req.param("uuid")?;
-    let uuid = Uuid::parse_str(uuid)?;
-    let command: Command = req.body_json().await?;
-    let state = req.state();
-    let mut surrounding: Option<game::Surroundings> = None;
-    let mut bomb_status: Option<game::BombStatus> = None;
-    let mut bomb_surroundings: Option<game::Surroundings> = None;
-    for command in flatten(&command) {
-        match command {
-            Commands::MoveBomberman { direction } => match direction {
-                Direction::Up => state.apply_to_game(uuid, (), |g| g.bomberman_up()),
-                Direction::Down => state.apply_to_game(uuid, (), |g| g.bomberman_down()),
-                Direction::Left => state.apply_to_game(uuid, (), |g| g.bomberman_left()),
-                Direction::Right => state.apply_to_game(uuid, (), |g| g.bomberman_right()),
-            },
-            Commands::FetchSurrounding => {
-                surrounding = state.apply_to_game(uuid, None, |g| Some(g.surrounding()))
+        }
+    }
+    pusher.cancel().await;
+    Ok(())
+}
+
+// Scripted games played over HTTP against golden responses in tests/golden.
+// Set UPDATE_GOLDEN=1 to rewrite them after an intended wire format change.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use serde_json::{json, Value};
+    use tide::http::{Method, Url};
+
+    struct Client {
+        app: tide::Server<state::State>,
+    }
+
+    impl Client {
+        fn new() -> Client {
+            let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+            let state = state::new(16).with_clock(game::Clock::Manual(start));
+            Client { app: app(state) }
+        }
+
+        async fn call(
+            &self,
+            method: Method,
+            path: &str,
+            token: Option<&str>,
+            body: Option<Value>,
+        ) -> (u16, Value) {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = tide::http::Request::new(method, url);
+            if let Some(token) = token {
+                req.insert_header("X-Player-Token", token);
             }
-            Commands::PlantBomb => state.apply_to_game(uuid, (), |g| g.plant_bomb()),
-            Commands::FetchBombStatus => {
-                bomb_status = state.apply_to_game(uuid, None, |g| g.bomb_status())
+            if let Some(body) = body {
+                req.set_body(tide::Body::from_json(&body).unwrap());
             }
-            Commands::FetchBombSurrounding => {
-                bomb_surroundings = state.apply_to_game(uuid, None, |g| g.bomb_surrounding())
+            let mut res: tide::http::Response = self.app.respond(req).await.unwrap();
+            let text = res.body_string().await.unwrap();
+            let body = if text.is_empty() {
+                Value::Null
+            } else {
+                serde_json::from_str(&text).unwrap()
+            };
+            (res.status() as u16, body)
+        }
+
+        // For endpoints that trade in plain text rather than JSON.
+        async fn text(
+            &self,
+            method: Method,
+            path: &str,
+            admin_key: Option<&str>,
+            body: &str,
+        ) -> (u16, String) {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = tide::http::Request::new(method, url);
+            if let Some(key) = admin_key {
+                req.insert_header("X-Admin-Key", key);
             }
+            req.set_body(body);
+            let mut res: tide::http::Response = self.app.respond(req).await.unwrap();
+            (res.status() as u16, res.body_string().await.unwrap())
+        }
+
+        // The new game and its creator's token.
+        async fn new_game(&self, query: &str) -> (Uuid, String) {
+            let path = format!("/v1/game/new/small_1?{}", query);
+            let (status, body) = self.call(Method::Post, &path, None, None).await;
+            assert_eq!(status, 201);
+            (
+                Uuid::parse_str(body["uuid"].as_str().unwrap()).unwrap(),
+                body["token"].as_str().unwrap().to_string(),
+            )
+        }
+
+        fn advance(&self, uuid: Uuid, millis: i64) {
+            self.app.state().apply_to_game(uuid, (), |g| {
+                g.advance(chrono::Duration::milliseconds(millis))
+            });
+        }
+    }
+
+    fn check_golden(name: &str, actual: &Value) {
+        let path = format!("{}/tests/golden/{}.json", env!("CARGO_MANIFEST_DIR"), name);
+        if env::var("UPDATE_GOLDEN").is_ok() {
+            std::fs::write(&path, serde_json::to_string_pretty(actual).unwrap() + "\n").unwrap();
+            return;
+        }
+        let expected: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap())
+            .unwrap_or_else(|e| panic!("Unreadable golden file {}: {}", path, e));
+        assert_eq!(actual, &expected, "{} differs from its golden file", name);
+    }
+
+    fn step(commands: &[Value]) -> Value {
+        let mut chain = Value::Null;
+        for command in commands.iter().rev() {
+            chain = json!({ "command": command, "additional": chain });
+        }
+        chain
+    }
+
+    // Plants a bomb, steps off too late and is caught by the blast.
+    async fn script(version: &str) -> Value {
+        let client = Client::new();
+        let (uuid, token) = client.new_game("seed=1").await;
+        let path = format!("/{}/game/{}", version, uuid);
+        let right = json!({"name": "MoveBomberman", "direction": "Right"});
+        let left = json!({"name": "MoveBomberman", "direction": "Left"});
+        let steps = [
+            (0, step(&[json!({"name": "FetchSurrounding"})])),
+            (
+                300,
+                step(&[
+                    right.clone(),
+                    right,
+                    json!({"name": "PlantBomb"}),
+                    json!({"name": "FetchBombStatus"}),
+                    json!({"name": "FetchSurrounding"}),
+                ]),
+            ),
+            (
+                300,
+                step(&[
+                    left,
+                    json!({"name": "FetchBombSurrounding"}),
+                    json!({"name": "FetchGameStatus"}),
+                ]),
+            ),
+            (
+                4000,
+                step(&[
+                    json!({"name": "FetchGameStatus"}),
+                    json!({"name": "FetchBombStatus"}),
+                ]),
+            ),
+        ];
+        let mut responses = vec![];
+        for (millis, body) in steps.iter() {
+            client.advance(uuid, *millis);
+            let (status, body) = client
+                .call(Method::Post, &path, Some(&token), Some(body.clone()))
+                .await;
+            responses.push(json!({ "status": status, "body": body }));
+        }
+        json!(responses)
+    }
+
+    #[async_std::test]
+    async fn test_v1_golden() {
+        check_golden("v1", &script("v1").await);
+    }
+
+    #[async_std::test]
+    async fn test_v2_golden() {
+        check_golden("v2", &script("v2").await);
+    }
+
+    #[async_std::test]
+    async fn test_v3_golden() {
+        check_golden("v3", &script("v3").await);
+    }
+
+    #[async_std::test]
+    async fn test_v4_golden() {
+        check_golden("v4", &script("v4").await);
+    }
+
+    #[async_std::test]
+    async fn test_hidden_games_are_listed_for_admins_only() {
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let client = Client {
+            app: app(state::new(16)
+                .with_clock(game::Clock::Manual(start))
+                .with_admin_key(Some("secret".to_string()))),
+        };
+        let (listed, _) = client.new_game("").await;
+        let (hidden, _) = client.new_game("hidden=true").await;
+        let listing = |body: Value| -> Vec<String> {
+            let games = body.as_array().unwrap().iter();
+            games
+                .map(|g| g["uuid"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let (_, body) = client.call(Method::Get, "/v1/game/", None, None).await;
+        assert_eq!(listing(body), vec![listed.to_string()]);
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost/v1/game/").unwrap(),
+        );
+        req.insert_header("X-Admin-Key", "secret");
+        let mut res: tide::http::Response = client.app.respond(req).await.unwrap();
+        let body: Value = res.body_json().await.unwrap();
+        assert_eq!(listing(body), vec![hidden.to_string(), listed.to_string()]);
+    }
+
+    #[async_std::test]
+    async fn test_hidden_cells_are_shown_to_admins_only() {
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let client = Client {
+            app: app(state::new(16)
+                .with_clock(game::Clock::Manual(start))
+                .with_admin_key(Some("secret".to_string()))),
+        };
+        let rows = json!(["XXXXXX", "XM fHX", "XXXXXX"]);
+        let template = json!({"name": "cache", "rows": rows});
+        let (status, _) = client
+            .call(Method::Post, "/v1/template", None, Some(template))
+            .await;
+        assert_eq!(status, 201);
+        let (_, body) = client
+            .call(Method::Post, "/v1/game/new/cache", None, None)
+            .await;
+        let uuid = body["uuid"].as_str().unwrap();
+        let created = |log: &str| -> Value {
+            let first: Value = serde_json::from_str(log.lines().next().unwrap()).unwrap();
+            first["template"].clone()
+        };
+        let log = format!("/v1/game/{}/log", uuid);
+        let (_, public) = client.text(Method::Get, &log, None, "").await;
+        assert_eq!(created(&public), json!(["XXXXXX", "XM BBX", "XXXXXX"]));
+        let (_, full) = client.text(Method::Get, &log, Some("secret"), "").await;
+        assert_eq!(created(&full), rows);
+        let board = format!("/v1/game/{}/board?hidden=true", uuid);
+        let (status, _) = client.text(Method::Get, &board, None, "").await;
+        assert_eq!(status, 403);
+        let (status, _) = client.text(Method::Get, &board, Some("guess"), "").await;
+        assert_eq!(status, 403);
+        let (status, _) = client.text(Method::Get, &board, Some("secret"), "").await;
+        assert_eq!(status, 200);
+        let (status, _) = client
+            .text(Method::Post, "/v1/game/replay", None, &full)
+            .await;
+        assert_eq!(status, 201);
+        // Without its gate the masked map no longer passes validation.
+        let (status, _) = client
+            .text(Method::Post, "/v1/game/replay", None, &public)
+            .await;
+        assert_eq!(status, 400);
+    }
+
+    #[async_std::test]
+    async fn test_templates_with_reserved_names_are_rejected() {
+        let client = Client::new();
+        let rows = json!(["XXXX", "XMOX", "XXXX"]);
+        for (name, status) in [
+            ("Random", 400),
+            ("generated", 400),
+            ("small_1", 409),
+            ("tiny", 201),
+        ] {
+            let body = json!({"name": name, "rows": rows});
+            let (actual, _) = client
+                .call(Method::Post, "/v1/template", None, Some(body))
+                .await;
+            assert_eq!(actual, status);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_stream_is_refused_before_upgrading() {
+        let client = Client::new();
+        let (uuid, token) = client.new_game("").await;
+        let path = format!("/v4/game/{}/stream", uuid);
+        let unknown = format!("/v4/game/{}/stream", Uuid::nil());
+        for (path, token, status) in [
+            (&unknown, Some(&*token), 404),
+            (&path, None, 401),
+            (&path, Some("not-a-token"), 403),
+            (&path, Some(&*Uuid::nil().to_string()), 403),
+        ] {
+            let (actual, body) = client.call(Method::Get, path, token, None).await;
+            assert_eq!(
+                (actual, body["status"].as_u64()),
+                (status, Some(status.into()))
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn test_token_query_is_for_streams_only() {
+        let client = Client::new();
+        let (uuid, token) = client.new_game("").await;
+        let command = format!("/v3/game/{}?token={}", uuid, token);
+        let plant = step(&[json!({"name": "PlantBomb"})]);
+        let (status, _) = client.call(Method::Post, &command, None, Some(plant)).await;
+        assert_eq!(status, 401);
+        let stream = format!("/v4/game/{}/stream?token={}", uuid, token);
+        // Past the token check, only the missing upgrade headers are refused.
+        let (status, _) = client.text(Method::Get, &stream, None, "").await;
+        assert_eq!(status, 426);
+    }
+
+    #[async_std::test]
+    async fn test_errors_golden() {
+        let client = Client::new();
+        let (uuid, token) = client.new_game("").await;
+        let unknown = Uuid::nil().to_string();
+        let plant = step(&[json!({"name": "PlantBomb"})]);
+        let bad = json!({"command": {"name": "Fly"}});
+        let game = |version: &str| format!("/{}/game/{}", version, uuid);
+        let mut responses = vec![];
+        for (path, token, body) in [
+            (
+                format!("/v1/game/{}", unknown),
+                Some(&*token),
+                plant.clone(),
+            ),
+            (game("v2"), Some(&*token), bad.clone()),
+            (game("v4"), Some(&*token), json!([bad["command"]])),
+            (game("v3"), None, plant.clone()),
+            (game("v3"), Some("not-a-token"), plant.clone()),
+            (game("v3"), Some(&*unknown), plant),
+            ("/v1/game/new/nowhere".to_string(), None, Value::Null),
+            (
+                "/v1/game/new/small_1?bombs=18446744073709551615".to_string(),
+                None,
+                Value::Null,
+            ),
+            (
+                "/v1/game/new/small_1?blast_radius=18446744073709551615".to_string(),
+                None,
+                Value::Null,
+            ),
+            (format!("/v1/game/{}/join", uuid), None, Value::Null),
+        ] {
+            let (status, body) = client.call(Method::Post, &path, token, Some(body)).await;
+            let path = path.replace(&uuid.to_string(), "{uuid}");
+            responses.push(json!({ "path": path, "status": status, "body": body }));
         }
+        check_golden("errors", &json!(responses));
     }
-    responses::command3(&surrounding, &bomb_status, &bomb_surroundings)
 }
@@ -3,19 +3,84 @@ use tide::prelude::*;
 use tide::{Body, Response, StatusCode};
 use uuid::Uuid;
 
+use crate::game::{Commands, Reply};
+
 #[derive(Debug)]
 pub struct NewGame {
     pub uuid: Uuid,
+    pub token: Uuid,
     pub width: usize,
     pub height: usize,
+    pub seed: Option<u64>,
 }
 
 pub fn new_game_created(new_game: &NewGame) -> tide::Result {
+    let mut body = json!({
+        "uuid": new_game.uuid.to_string(),
+        "token": new_game.token.to_string(),
+        "height": new_game.height,
+        "width": new_game.width});
+    if let Some(seed) = new_game.seed {
+        body["seed"] = json!(seed);
+    }
+    Ok(Response::builder(StatusCode::Created)
+        .body(Body::from_json(&body)?)
+        .build())
+}
+
+pub fn template_created(name: &str, rows: &[String]) -> tide::Result {
+    Ok(Response::builder(StatusCode::Created)
+        .body(Body::from_json(&json!({
+                "name": name.to_lowercase(),
+                "height": rows.len(),
+                "width": rows[0].chars().count()}))?)
+        .build())
+}
+
+pub fn player_joined(player: &crate::game::Player) -> tide::Result {
     Ok(Response::builder(StatusCode::Created)
         .body(Body::from_json(&json!({
-                "uuid": new_game.uuid.to_string(),
-                "height": new_game.height,
-                "width": new_game.width}))?)
+                "player": player.index,
+                "token": player.token.to_string()}))?)
+        .build())
+}
+
+// One JSON object per line, oldest event first.
+pub fn game_log(log: &[crate::game::LogEntry]) -> tide::Result {
+    let mut body = String::new();
+    for entry in log {
+        body.push_str(&serde_json::to_string(entry)?);
+        body.push('\n');
+    }
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type("application/x-ndjson")
+        .body(body)
+        .build())
+}
+
+// Replaces the body of every error response with {"status", "error"}.
+pub async fn error(mut res: Response) -> tide::Result {
+    if let Some(message) = res.error().map(|e| e.to_string()) {
+        let status = res.status();
+        res.set_body(Body::from_json(&json!({
+            "status": status as u16,
+            "error": message,
+        }))?);
+    }
+    Ok(res)
+}
+
+pub fn board(rows: &[String]) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(tide::http::mime::PLAIN)
+        .body(rows.join("\n") + "\n")
+        .build())
+}
+
+pub fn html(page: &'static str) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(tide::http::mime::HTML)
+        .body(page)
         .build())
 }
 
@@ -27,30 +92,70 @@ fn active_game_json(g: &crate::state::ActiveGame) -> Value {
     })
 }
 
-pub fn list_games(games: &Vec<crate::state::ActiveGame>) -> tide::Result {
+pub fn list_games(games: &[crate::state::ActiveGame]) -> tide::Result {
     let entries: Vec<Value> = games.iter().map(active_game_json).collect();
     Ok(Response::builder(StatusCode::Ok)
         .body(Body::from_json(&json![entries])?)
         .build())
 }
 
-pub fn command(surrounding: &Option<crate::game::Surroundings>) -> tide::Result {
-    match surrounding {
-        None => Ok(Response::builder(StatusCode::Ok).build()),
-        Some(s) => Ok(Response::builder(StatusCode::Ok)
-            .body(Body::from_json(&json!({
-                "surrounding": json!({
-                    "bombermans": s.bombermans,
-                    "ghosts": s.ghosts,
-                    "wall": s.wall,
-                    "bricks": s.bricks,
-                    "gates": s.gates,
-                })
-            }))?)
-            .build()),
+// Encodes the replies to a command chain for one protocol version.
+pub type Encoder = fn(&[Commands], &[Reply], &Option<crate::game::Outcome>) -> Value;
+
+// A null body is sent as no body at all.
+pub fn encoded(body: Value) -> tide::Result {
+    if body.is_null() {
+        return Ok(Response::builder(StatusCode::Ok).build());
+    }
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&body)?)
+        .build())
+}
+
+fn results(replies: &[Reply]) -> Vec<Value> {
+    replies
+        .iter()
+        .map(|reply| match reply {
+            Reply::Done(result) => result_json(result),
+            // Commands the version does not know of are not run at all.
+            Reply::Skipped => json!("unsupported"),
+            _ => result_json(&Ok(())),
+        })
+        .collect()
+}
+
+// Versions up to v3 return a single value per fetch; the last one wins.
+fn last<'a, T>(replies: &'a [Reply], pick: impl Fn(&'a Reply) -> Option<T>) -> Option<T> {
+    replies.iter().rev().find_map(pick)
+}
+
+fn surrounding(reply: &Reply) -> Option<&crate::game::Surroundings> {
+    match reply {
+        Reply::Surrounding(s) => Some(s),
+        _ => None,
     }
 }
 
+// Versions before v4 only know one bomb: the one due first.
+fn bomb(reply: &Reply) -> Option<Option<&crate::game::BombStatus>> {
+    match reply {
+        Reply::BombStatus(b) => Some(b.first()),
+        _ => None,
+    }
+}
+
+pub fn command(
+    _commands: &[Commands],
+    replies: &[Reply],
+    _outcome: &Option<crate::game::Outcome>,
+) -> Value {
+    last(replies, surrounding).map_or(Value::Null, |s| {
+        json!({
+            "surrounding": plain_surr_json(s),
+        })
+    })
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct List {
     pub head: Option<(usize, usize)>,
@@ -72,29 +177,136 @@ fn to_list(vs: &Vec<(usize, usize)>) -> List {
 }
 
 pub fn command2(
-    surrounding: &Option<crate::game::Surroundings>,
-    bomb: &Option<crate::game::BombStatus>,
-) -> tide::Result {
-    Ok(Response::builder(StatusCode::Ok)
-        .body(Body::from_json(&json!({
-            "surrounding": surrounding.as_ref().map(|v| surr_json(v)),
-            "bomb": bomb.as_ref().map(|v| json!(v.coords)),
-        }))?)
-        .build())
+    _commands: &[Commands],
+    replies: &[Reply],
+    outcome: &Option<crate::game::Outcome>,
+) -> Value {
+    json!({
+        "surrounding": last(replies, surrounding).map(surr_json),
+        "bomb": last(replies, bomb).flatten().map(|v| json!(v.coords)),
+        "outcome": outcome,
+    })
 }
 
+// Per-command results start with v3; older clients keep their exact replies.
 pub fn command3(
-    surrounding: &Option<crate::game::Surroundings>,
-    bomb: &Option<crate::game::BombStatus>,
+    _commands: &[Commands],
+    replies: &[Reply],
+    outcome: &Option<crate::game::Outcome>,
+) -> Value {
+    let bomb_surrounding = last(replies, |r| match r {
+        Reply::BombSurrounding(s) => Some(s.as_ref()),
+        _ => None,
+    });
+    let game_status = last(replies, |r| match r {
+        Reply::GameStatus(s) => Some(s),
+        _ => None,
+    });
+    json!({
+        "surrounding": last(replies, surrounding).map(surr_json),
+        "bomb": last(replies, bomb).flatten().map(|v| json!(v.coords)),
+        "bomb_status": last(replies, bomb).flatten().map(bomb_json),
+        "bomb_surrounding": bomb_surrounding.flatten().map(bomb_surr_json),
+        "status": game_status.map(game_status_json),
+        "outcome": outcome,
+        "results": results(replies),
+    })
+}
+
+// One entry per command, in order: the command as sent, its result and, for
+// fetches, what was fetched.
+pub fn command4(
+    commands: &[Commands],
+    replies: &[Reply],
+    _outcome: &Option<crate::game::Outcome>,
+) -> Value {
+    let entries: Vec<Value> = commands
+        .iter()
+        .zip(results(replies))
+        .zip(replies)
+        .map(|((command, result), reply)| {
+            let mut entry = json!(command);
+            entry["result"] = result;
+            match reply {
+                Reply::Done(_) | Reply::Skipped => {}
+                Reply::Surrounding(s) => entry["surrounding"] = full_surr_json(s),
+                Reply::BombStatus(b) => {
+                    entry["bomb"] = json!(b.first().map(|v| v.coords));
+                    entry["bombs"] = bombs_json(b);
+                }
+                Reply::BombSurrounding(s) => {
+                    entry["bomb_surrounding"] = json!(s.as_ref().map(bomb_surr_json))
+                }
+                Reply::GameStatus(s) => entry["status"] = game_status_json(s),
+            }
+            entry
+        })
+        .collect();
+    json!(entries)
+}
+
+pub fn frame(
+    surrounding: &crate::game::Surroundings,
+    bombs: &[crate::game::BombStatus],
     bomb_surrounding: &Option<crate::game::Surroundings>,
-) -> tide::Result {
-    Ok(Response::builder(StatusCode::Ok)
-        .body(Body::from_json(&json!({
-            "surrounding": surrounding.as_ref().map(|v| surr_json(v)),
-            "bomb": bomb.as_ref().map(|v| json!(v.coords)),
-            "bomb_surrounding": bomb_surrounding.as_ref().map(|v| bomb_surr_json(v)),
-        }))?)
-        .build())
+    game_status: &crate::game::GameStatus,
+) -> Value {
+    json!({
+        "surrounding": full_surr_json(surrounding),
+        "bomb": bombs.first().map(|v| json!(v.coords)),
+        "bombs": bombs_json(bombs),
+        "bomb_surrounding": bomb_surrounding.as_ref().map(bomb_surr_json),
+        "status": game_status_json(game_status),
+    })
+}
+
+fn bomb_json(b: &crate::game::BombStatus) -> Value {
+    json!({
+        "coords": b.coords,
+        "planted": b.planted,
+        "fuse": b.fuse,
+        "turns": b.turns,
+        "area": b.area,
+    })
+}
+
+fn bombs_json(bombs: &[crate::game::BombStatus]) -> Value {
+    bombs.iter().map(bomb_json).collect()
+}
+
+fn result_json(result: &Result<(), crate::game::Rejection>) -> Value {
+    use crate::game::Rejection;
+    json!(match result {
+        Ok(()) => "accepted",
+        Err(Rejection::Blocked) => "blocked_by_wall",
+        Err(Rejection::BlockedByBomb) => "blocked_by_bomb",
+        Err(Rejection::Throttled) => "throttled",
+        Err(Rejection::GameOver) => "game_over",
+        Err(Rejection::NoBombsLeft) => "no_bombs_left",
+        Err(Rejection::BombInPlace) => "bomb_in_place",
+    })
+}
+
+fn plain_surr_json(s: &crate::game::Surroundings) -> Value {
+    json!({
+        "bombermans": s.bombermans,
+        "ghosts": s.ghosts,
+        "wall": s.wall,
+        "bricks": s.bricks,
+        "gates": s.gates,
+    })
+}
+
+// The plain encoding plus what versions up to v3 don't know about.
+fn full_surr_json(s: &crate::game::Surroundings) -> Value {
+    let mut result = plain_surr_json(s);
+    result["power_ups"] = s
+        .power_ups
+        .iter()
+        .map(|(coords, kind)| json!({"coords": coords, "kind": kind}))
+        .collect();
+    result["bombs"] = json!(s.bombs);
+    result
 }
 
 fn surr_json(s: &crate::game::Surroundings) -> serde_json::Value {
@@ -113,3 +325,122 @@ fn bomb_surr_json(s: &crate::game::Surroundings) -> serde_json::Value {
         "bricks": s.bricks,
     })
 }
+
+fn game_status_json(s: &crate::game::GameStatus) -> serde_json::Value {
+    json!({
+        "status": s.status,
+        "outcome": s.outcome,
+        "elapsed": s.elapsed,
+        "turn": s.turn,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{BombStatus, Direction, Rejection, Surroundings};
+
+    fn chain() -> (Vec<Commands>, Vec<Reply>) {
+        let surroundings = || Surroundings {
+            bricks: vec![(1, 2)],
+            wall: vec![(0, 1)],
+            bombermans: vec![(1, 1)],
+            ghosts: vec![],
+            gates: vec![],
+            power_ups: vec![],
+            bombs: vec![],
+        };
+        let commands = vec![
+            Commands::MoveBomberman {
+                direction: Direction::Up,
+            },
+            Commands::PlantBomb,
+            Commands::FetchBombStatus,
+            Commands::FetchSurrounding,
+        ];
+        let replies = vec![
+            Reply::Done(Err(Rejection::Blocked)),
+            Reply::Done(Ok(())),
+            Reply::BombStatus(vec![
+                BombStatus {
+                    coords: (1, 1),
+                    planted: 0,
+                    fuse: 1500,
+                    turns: None,
+                    area: vec![(1, 1), (1, 2)],
+                },
+                BombStatus {
+                    coords: (1, 3),
+                    planted: 2300,
+                    fuse: 3800,
+                    turns: None,
+                    area: vec![(1, 3)],
+                },
+            ]),
+            Reply::Surrounding(surroundings()),
+        ];
+        (commands, replies)
+    }
+
+    #[test]
+    fn test_versions_keep_their_wire_format() {
+        let (commands, replies) = chain();
+        let results = json!(["blocked_by_wall", "accepted", "accepted", "accepted"]);
+        assert_eq!(
+            command(&commands, &replies, &None),
+            json!({
+                "surrounding": {
+                    "bombermans": [[1, 1]], "ghosts": [], "wall": [[0, 1]],
+                    "bricks": [[1, 2]], "gates": []
+                }
+            })
+        );
+        let list = |c: [usize; 2]| json!({"head": c, "tail": {"head": null, "tail": null}});
+        let empty = json!({"head": null, "tail": null});
+        assert_eq!(
+            command2(&commands, &replies, &None),
+            json!({
+                "surrounding": {
+                    "bombermans": list([1, 1]), "ghosts": empty, "wall": list([0, 1]),
+                    "bricks": list([1, 2]), "gates": empty
+                },
+                "bomb": [1, 1],
+                "outcome": null,
+            })
+        );
+        assert_eq!(command(&commands, &replies[..3], &None), Value::Null);
+        let v3 = command3(&commands, &replies, &None);
+        assert_eq!(v3["bomb"], json!([1, 1]));
+        assert_eq!(
+            v3["bomb_status"],
+            json!({"coords": [1, 1], "planted": 0, "fuse": 1500, "turns": null, "area": [[1, 1], [1, 2]]})
+        );
+        assert_eq!(v3["bomb_surrounding"], Value::Null);
+        assert_eq!(v3["status"], Value::Null);
+        assert_eq!(v3["results"], results);
+        assert_eq!(
+            v3["surrounding"],
+            command2(&commands, &replies, &None)["surrounding"]
+        );
+        let v4 = command4(&commands, &replies, &None);
+        assert_eq!(
+            v4[0],
+            json!({"name": "MoveBomberman", "direction": "Up", "result": "blocked_by_wall"})
+        );
+        assert_eq!(
+            v4[2],
+            json!({
+                "name": "FetchBombStatus", "result": "accepted", "bomb": [1, 1],
+                "bombs": [v3["bomb_status"], {
+                    "coords": [1, 3], "planted": 2300, "fuse": 3800, "turns": null, "area": [[1, 3]]
+                }]
+            })
+        );
+        assert_eq!(v4.as_array().unwrap().len(), 4);
+        let skipped = [Reply::Skipped, Reply::Done(Ok(()))];
+        assert_eq!(
+            command3(&commands[1..3], &skipped, &None)["results"],
+            json!(["unsupported", "accepted"])
+        );
+    }
+}
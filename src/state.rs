@@ -1,4 +1,5 @@
-use super::game::Game;
+use super::game::{Clock, Game};
+use super::storage::{self, Storage};
 use chrono::prelude::*;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
@@ -8,7 +9,11 @@ use uuid::Uuid;
 pub struct State {
     games: Arc<RwLock<HashMap<Uuid, Game>>>,
     stack: Arc<RwLock<Vec<Uuid>>>,
+    templates: Arc<RwLock<HashMap<String, Vec<String>>>>,
     size: usize,
+    storage: Option<storage::Writer>,
+    clock: Clock,
+    admin_key: Option<String>,
 }
 
 #[derive(Debug)]
@@ -19,6 +24,24 @@ pub struct ActiveGame {
 }
 
 impl State {
+    // The clock new games start on.
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    pub fn with_admin_key(self, admin_key: Option<String>) -> State {
+        State { admin_key, ..self }
+    }
+
+    pub fn is_admin_key(&self, key: &str) -> bool {
+        self.admin_key.as_deref() == Some(key)
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(self, clock: Clock) -> State {
+        State { clock, ..self }
+    }
+
     pub fn insert_and_evict(&self, game: Game) -> Uuid {
         let uuid = Uuid::new_v4();
         let mut games = self.games.write().unwrap();
@@ -30,8 +53,24 @@ impl State {
         } else {
             0
         };
+        let mut evicted = vec![];
         for _ in 0..diff {
-            games.remove(&stack.remove(0));
+            let old = stack.remove(0);
+            games.remove(&old);
+            evicted.push(old);
+        }
+        if let Some(writer) = &self.storage {
+            let changes = storage::changes(uuid, &games[&uuid], 0);
+            let stack = stack.clone();
+            writer.queue(move |storage| {
+                let saved = changes
+                    .and_then(|c| storage.save_game(&c))
+                    .and_then(|_| storage.save_order(&stack))
+                    .and_then(|_| evicted.iter().try_for_each(|e| storage.remove_game(*e)));
+                if let Err(e) = saved {
+                    eprintln!("Failed to store game {}: {}", uuid, e);
+                }
+            });
         }
         uuid
     }
@@ -42,13 +81,51 @@ impl State {
     {
         let mut games = self.games.write().unwrap();
         if let Some(game) = games.get_mut(&uuid) {
-            f(game)
+            let (updated, logged) = (game.updated, game.log().len());
+            let result = f(game);
+            if let Some(writer) = &self.storage {
+                // Encoded under the lock, written once it is released.
+                if game.updated != updated || game.log().len() != logged {
+                    let changes = storage::changes(uuid, game, logged);
+                    writer.queue(move |storage| {
+                        if let Err(e) = changes.and_then(|c| storage.save_game(&c)) {
+                            eprintln!("Failed to store game {}: {}", uuid, e);
+                        }
+                    });
+                }
+            }
+            result
         } else {
             default
         }
     }
 
-    pub fn list_games(&self) -> Vec<ActiveGame> {
+    // Returns false when a template with that name is already stored.
+    pub fn insert_template(&self, name: &str, rows: Vec<String>) -> bool {
+        let mut templates = self.templates.write().unwrap();
+        let name = name.to_lowercase();
+        if templates.contains_key(&name) {
+            return false;
+        }
+        templates.insert(name, rows);
+        if let Some(writer) = &self.storage {
+            let templates = templates.clone();
+            writer.queue(move |storage| {
+                if let Err(e) = storage.save_templates(&templates) {
+                    eprintln!("Failed to store templates: {}", e);
+                }
+            });
+        }
+        true
+    }
+
+    pub fn template(&self, name: &str) -> Option<Vec<String>> {
+        let templates = self.templates.read().unwrap();
+        templates.get(&name.to_lowercase()).cloned()
+    }
+
+    // Hidden games are only listed for admins.
+    pub fn list_games(&self, admin: bool) -> Vec<ActiveGame> {
         let stack = self.stack.write().unwrap();
         let mut uuids = stack.to_vec();
         uuids.reverse();
@@ -56,8 +133,11 @@ impl State {
         for uuid in uuids {
             let games = self.games.read().unwrap();
             let game = games.get(&uuid).unwrap();
+            if game.hidden && !admin {
+                continue;
+            }
             result.push(ActiveGame {
-                uuid: uuid,
+                uuid,
                 started: game.started,
                 updated: game.updated,
             });
@@ -70,27 +150,102 @@ pub fn new(size: usize) -> State {
     State {
         games: Arc::new(RwLock::new(HashMap::new())),
         stack: Arc::new(RwLock::new(Vec::new())),
-        size: size,
+        templates: Arc::new(RwLock::new(HashMap::new())),
+        size,
+        storage: None,
+        clock: Clock::default(),
+        admin_key: None,
     }
 }
 
+// Restores the games kept in storage and stores every later change to it.
+pub fn restore(size: usize, storage: Storage) -> std::io::Result<State> {
+    let (games, stack) = storage.load()?;
+    let templates = storage.load_templates()?;
+    Ok(State {
+        games: Arc::new(RwLock::new(games)),
+        stack: Arc::new(RwLock::new(stack)),
+        templates: Arc::new(RwLock::new(templates)),
+        size,
+        storage: Some(storage::writer(storage)),
+        clock: Clock::default(),
+        admin_key: None,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn uuids(gs: &Vec<ActiveGame>) -> Vec<Uuid> {
+    fn uuids(gs: &[ActiveGame]) -> Vec<Uuid> {
         gs.iter().map(|g| g.uuid).collect()
     }
 
     #[test]
     fn test_size() {
         let sut = new(2);
-        assert_eq!(sut.list_games().len(), 0);
-        let uuid_1 = sut.insert_and_evict(crate::game::new(crate::game::templates::SMALL_1));
-        assert_eq!(uuids(&sut.list_games()), vec![uuid_1]);
-        let uuid_2 = sut.insert_and_evict(crate::game::new(crate::game::templates::WIDE_1));
-        assert_eq!(uuids(&sut.list_games()), vec![uuid_2, uuid_1]);
-        let uuid_3 = sut.insert_and_evict(crate::game::new(crate::game::templates::WIDE_1));
-        assert_eq!(uuids(&sut.list_games()), vec![uuid_3, uuid_2]);
+        assert_eq!(sut.list_games(false).len(), 0);
+        let uuid_1 = sut.insert_and_evict(crate::game::new(
+            crate::game::templates::SMALL_1,
+            &Default::default(),
+        ));
+        assert_eq!(uuids(&sut.list_games(false)), vec![uuid_1]);
+        let uuid_2 = sut.insert_and_evict(crate::game::new(
+            crate::game::templates::WIDE_1,
+            &Default::default(),
+        ));
+        assert_eq!(uuids(&sut.list_games(false)), vec![uuid_2, uuid_1]);
+        let uuid_3 = sut.insert_and_evict(crate::game::new(
+            crate::game::templates::WIDE_1,
+            &Default::default(),
+        ));
+        assert_eq!(uuids(&sut.list_games(false)), vec![uuid_3, uuid_2]);
+    }
+
+    #[test]
+    fn test_admin_key() {
+        let sut = new(4).with_admin_key(Some(String::from("secret")));
+        assert!(sut.is_admin_key("secret"));
+        assert!(!sut.is_admin_key("guess"));
+        assert!(!sut.is_admin_key(""));
+        assert!(!new(4).is_admin_key(""));
+    }
+
+    #[test]
+    fn test_changes_are_stored_after_the_lock_is_released() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let dir = dir.to_str().unwrap();
+        let sut = restore(2, storage::new(dir).unwrap()).unwrap();
+        let uuid = sut.insert_and_evict(crate::game::new(
+            crate::game::templates::ARENA_1,
+            &Default::default(),
+        ));
+        let joined = sut.apply_to_game(uuid, None, |g| g.join().ok());
+        assert!(joined.is_some());
+        let (done, written) = std::sync::mpsc::channel();
+        sut.storage
+            .as_ref()
+            .unwrap()
+            .queue(move |_| done.send(()).unwrap());
+        written.recv().unwrap();
+        let restored = restore(2, storage::new(dir).unwrap()).unwrap();
+        let log = |s: &State| s.apply_to_game(uuid, vec![], |g| g.log().to_vec());
+        assert_eq!(log(&restored), log(&sut));
+        assert_eq!(log(&restored).len(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_templates() {
+        let sut = new(2);
+        let rows = vec![
+            String::from("XXXX"),
+            String::from("XMOX"),
+            String::from("XXXX"),
+        ];
+        assert!(sut.insert_template("Tiny", rows.clone()));
+        assert!(!sut.insert_template("tiny", rows.clone()));
+        assert_eq!(sut.template("TINY"), Some(rows));
+        assert_eq!(sut.template("other"), None);
     }
 }
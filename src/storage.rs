@@ -0,0 +1,218 @@
+use super::game::{Game, LogEntry};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use uuid::Uuid;
+
+const ORDER_FILE: &str = "order.json";
+const TEMPLATES_FILE: &str = "templates.json";
+
+// A directory holding one JSON snapshot and one event log per game plus the
+// eviction order.
+#[derive(Debug)]
+pub struct Storage {
+    dir: PathBuf,
+}
+
+// A game as it is to be stored: a fresh snapshot plus the log entries added
+// since the last save.
+pub struct Changes {
+    uuid: Uuid,
+    snapshot: Vec<u8>,
+    log: Vec<u8>,
+}
+
+// Encodes the game and its log from entry `logged` on.
+pub fn changes(uuid: Uuid, game: &Game, logged: usize) -> io::Result<Changes> {
+    Ok(Changes {
+        uuid,
+        snapshot: serde_json::to_vec(game)?,
+        log: log_lines(&game.log()[logged..])?,
+    })
+}
+
+impl Storage {
+    // The log only ever grows, so it is appended to, one JSON object per line.
+    pub fn save_game(&self, changes: &Changes) -> io::Result<()> {
+        self.write(&format!("{}.json", changes.uuid), &changes.snapshot)?;
+        if changes.log.is_empty() {
+            return Ok(());
+        }
+        fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.dir.join(format!("{}.log", changes.uuid)))?
+            .write_all(&changes.log)
+    }
+
+    pub fn remove_game(&self, uuid: Uuid) -> io::Result<()> {
+        for name in [format!("{}.json", uuid), format!("{}.log", uuid)] {
+            match fs::remove_file(self.dir.join(name)) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                other => other?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save_order(&self, stack: &[Uuid]) -> io::Result<()> {
+        self.write(ORDER_FILE, &serde_json::to_vec(stack)?)
+    }
+
+    pub fn save_templates(&self, templates: &HashMap<String, Vec<String>>) -> io::Result<()> {
+        self.write(TEMPLATES_FILE, &serde_json::to_vec(templates)?)
+    }
+
+    pub fn load_templates(&self) -> io::Result<HashMap<String, Vec<String>>> {
+        match fs::read(self.dir.join(TEMPLATES_FILE)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Games missing from the order file, or failing to parse, are skipped.
+    pub fn load(&self) -> io::Result<(HashMap<Uuid, Game>, Vec<Uuid>)> {
+        let order = match fs::read(self.dir.join(ORDER_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        let mut games = HashMap::new();
+        let mut stack = vec![];
+        for uuid in order {
+            match self.read_game(uuid) {
+                Ok(game) => {
+                    games.insert(uuid, game);
+                    stack.push(uuid);
+                }
+                Err(e) => eprintln!("Skipping stored game {}: {}", uuid, e),
+            }
+        }
+        Ok((games, stack))
+    }
+
+    fn read_game(&self, uuid: Uuid) -> anyhow::Result<Game> {
+        let bytes = fs::read(self.dir.join(format!("{}.json", uuid)))?;
+        let mut game: Game = serde_json::from_slice(&bytes)?;
+        let log = format!("{}.log", uuid);
+        let text = fs::read_to_string(self.dir.join(&log))?;
+        // A crash mid-append leaves a last line without its newline; it is
+        // dropped, and cut from the file so later entries start afresh.
+        game.restore_log(
+            text.split_inclusive('\n')
+                .filter(|l| l.ends_with('\n'))
+                .map(serde_json::from_str)
+                .collect::<Result<_, _>>()?,
+        );
+        if !text.ends_with('\n') {
+            self.write(&log, &log_lines(game.log())?)?;
+        }
+        Ok(game)
+    }
+
+    // Writes go through a temporary file so a crash never leaves half a snapshot.
+    fn write(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        fs::write(&tmp, bytes)?;
+        fs::rename(tmp, self.dir.join(name))
+    }
+}
+
+fn log_lines(entries: &[LogEntry]) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    for entry in entries {
+        serde_json::to_writer(&mut bytes, entry)?;
+        bytes.push(b'\n');
+    }
+    Ok(bytes)
+}
+
+type Job = Box<dyn FnOnce(&Storage) + Send>;
+
+// Runs writes on a thread of their own, in the order they were queued, so
+// they can be queued under the games lock and hit the disk after it is
+// released.
+#[derive(Clone)]
+pub struct Writer {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Writer {
+    pub fn queue(&self, job: impl FnOnce(&Storage) + Send + 'static) {
+        if self.jobs.send(Box::new(job)).is_err() {
+            eprintln!("Storage writer has stopped");
+        }
+    }
+}
+
+pub fn writer(storage: Storage) -> Writer {
+    let (jobs, queued) = mpsc::channel::<Job>();
+    thread::spawn(move || {
+        for job in queued {
+            job(&storage);
+        }
+    });
+    Writer { jobs }
+}
+
+pub fn new(dir: &str) -> io::Result<Storage> {
+    fs::create_dir_all(dir)?;
+    Ok(Storage {
+        dir: PathBuf::from(dir),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let sut = new(dir.to_str().unwrap()).unwrap();
+        let uuid_1 = Uuid::new_v4();
+        let uuid_2 = Uuid::new_v4();
+        let game = crate::game::new(crate::game::templates::SMALL_1, &Default::default());
+        sut.save_game(&changes(uuid_1, &game, 0).unwrap()).unwrap();
+        sut.save_game(&changes(uuid_2, &game, 0).unwrap()).unwrap();
+        sut.remove_game(uuid_2).unwrap();
+        sut.save_order(&[uuid_1, uuid_2]).unwrap();
+        let (games, stack) = sut.load().unwrap();
+        assert_eq!(stack, vec![uuid_1]);
+        let restored = games.get(&uuid_1).unwrap();
+        assert_eq!(restored.width, game.width);
+        assert_eq!(restored.started, game.started);
+        assert_eq!(
+            serde_json::to_string(restored).unwrap(),
+            serde_json::to_string(&game).unwrap()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_is_appended_apart_from_the_snapshot() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let sut = new(dir.to_str().unwrap()).unwrap();
+        let uuid = Uuid::new_v4();
+        let mut game = crate::game::new(crate::game::templates::ARENA_1, &Default::default());
+        sut.save_game(&changes(uuid, &game, 0).unwrap()).unwrap();
+        game.join().unwrap();
+        sut.save_game(&changes(uuid, &game, 1).unwrap()).unwrap();
+        sut.save_order(&[uuid]).unwrap();
+        let snapshot = fs::read_to_string(dir.join(format!("{}.json", uuid))).unwrap();
+        assert!(!snapshot.contains("\"log\""));
+        let log = dir.join(format!("{}.log", uuid));
+        let mut file = fs::OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(b"{\"at\":").unwrap();
+        let (games, _) = sut.load().unwrap();
+        assert_eq!(games[&uuid].log(), game.log());
+        assert!(fs::read_to_string(&log).unwrap().ends_with('\n'));
+        sut.remove_game(uuid).unwrap();
+        assert!(!log.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}